@@ -0,0 +1,3956 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use minijinja::Environment;
+use serde::{Deserialize, Serialize};
+use sha2::{Sha256, Sha512, Digest};
+use std::fmt;
+use clap::ValueEnum;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgo {
+    #[default]
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+#[derive(Debug)]
+pub enum ManagerError {
+    Io(std::io::Error),
+    Template(minijinja::Error),
+    Yaml(serde_yaml::Error),
+    Json(serde_json::Error),
+    Toml(toml::de::Error),
+    TomlSerialize(toml::ser::Error),
+    TemplateNotFound(PathBuf),
+    IncludeNotFound {
+        template: String,
+        template_dir: PathBuf,
+        detail: String,
+    },
+    UndefinedVariables {
+        template: String,
+        service: Option<String>,
+        names: Vec<String>,
+        source: minijinja::Error,
+    },
+    TemplateFailed {
+        service: String,
+        path: PathBuf,
+        line: Option<usize>,
+        column: Option<usize>,
+        source: minijinja::Error,
+    },
+    MissingVariables(Vec<(String, Vec<String>)>),
+    MissingEnvVar {
+        name: String,
+        service: String,
+        variable: String,
+    },
+    UnitDirNotFound(PathBuf),
+    CreateDirFailed {
+        path: PathBuf,
+        source: io::Error,
+    },
+    WriteFailed {
+        path: PathBuf,
+        source: io::Error,
+    },
+    WriteMismatch(PathBuf),
+    LeftoverSyntax {
+        unit: String,
+        line: usize,
+    },
+    MissingSection {
+        unit: String,
+        section: &'static str,
+    },
+    CanaryStopped(String),
+    UserUnitDirUnknown,
+    SystemctlFailed {
+        unit: String,
+        action: String,
+        code: Option<i32>,
+        stderr: String,
+    },
+    VerifyFailed {
+        unit: String,
+        stderr: String,
+    },
+    RestartFailed {
+        unit: String,
+        source: Box<ManagerError>,
+    },
+    StateOutOfSync(String),
+    NotInteractive,
+    UnsupportedStateVersion(u64),
+    StateLocked(PathBuf),
+    CorruptState {
+        path: PathBuf,
+        reason: String,
+    },
+    DuplicateUnit {
+        unit: String,
+        first: String,
+        second: String,
+    },
+    DuplicateUnits(Vec<String>),
+    UnknownUnits {
+        unknown: Vec<String>,
+        valid: Vec<String>,
+    },
+    ServicesFailed(Vec<ManagerError>),
+    NoBackups(String),
+    BackupNotFound(PathBuf),
+    ServiceFailed {
+        unit: String,
+        source: Box<ManagerError>,
+    },
+    SystemctlTimedOut {
+        unit: String,
+        action: String,
+        timeout: u64,
+    },
+    HealthCheckFailed {
+        unit: String,
+        timeout: u64,
+        output: String,
+    },
+    UnknownUser(String),
+    UnknownGroup(String),
+    InvalidUnitName {
+        unit: String,
+        reason: &'static str,
+    },
+    InvalidTemplate {
+        unit: String,
+        reason: &'static str,
+    },
+    OutputExists(Vec<PathBuf>),
+    DependencyCycle(Vec<String>),
+    UnusedTemplates(Vec<String>),
+    NotInSync(Vec<String>),
+    HookFailed {
+        unit: String,
+        hook: &'static str,
+        code: Option<i32>,
+        output: String,
+    },
+    TemplateFileFailed {
+        template: String,
+        service: Option<String>,
+        path: PathBuf,
+        reason: String,
+    },
+    NothingToUndo(PathBuf),
+    UndoModified(Vec<String>),
+}
+
+impl fmt::Display for ManagerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManagerError::Io(err) => write!(f, "IO error: {}", err),
+            ManagerError::Template(err) => write!(f, "Template error: {}", err),
+            ManagerError::Yaml(err) => write!(f, "YAML error: {}", err),
+            ManagerError::Json(err) => write!(f, "JSON error: {}", err),
+            ManagerError::Toml(err) => write!(f, "TOML error: {}", err),
+            ManagerError::TomlSerialize(err) => write!(f, "TOML error: {}", err),
+            ManagerError::TemplateNotFound(path) => write!(f, "Template not found: {}", path.display()),
+            ManagerError::IncludeNotFound { template, template_dir, detail } => write!(f, "Template {} {} in {}", template, detail, template_dir.display()),
+            ManagerError::UndefinedVariables { template, service, names, source } => {
+                write!(f, "Template {}", template)?;
+                if let Some(service) = service {
+                    write!(f, " of {}", service)?;
+                }
+                if names.is_empty() {
+                    write!(f, " uses an undefined variable: {}", source)
+                } else {
+                    write!(f, " uses undefined variables {}: {}", names.join(", "), source)
+                }
+            }
+            ManagerError::TemplateFailed { service, path, line, column, source } => {
+                write!(f, "{}", path.display())?;
+                if let Some(line) = line {
+                    write!(f, ":{}", line)?;
+                }
+                if let Some(column) = column {
+                    write!(f, ":{}", column)?;
+                }
+                write!(f, ": {}", source.kind())?;
+                if let Some(detail) = source.detail() {
+                    write!(f, ": {}", detail)?;
+                }
+                write!(f, " (rendering {})", service)
+            }
+            ManagerError::MissingVariables(missing) => {
+                write!(f, "Services are missing variables used by their templates:")?;
+                for (unit, names) in missing {
+                    write!(f, "\n  {}: {}", unit, names.join(", "))?;
+                }
+                Ok(())
+            }
+            ManagerError::MissingEnvVar { name, service, variable } => write!(f, "Environment variable {} used by variable {} of {} is not set", name, variable, service),
+            ManagerError::UnitDirNotFound(path) => write!(f, "Unit directory not found: {}", path.display()),
+            ManagerError::CreateDirFailed { path, source } => write!(f, "Could not create {}: {}{}", path.display(), source, io_hint(source)),
+            ManagerError::WriteFailed { path, source } => write!(f, "Could not write {}: {}{}", path.display(), source, io_hint(source)),
+            ManagerError::CanaryStopped(unit) => write!(f, "Stopped after the canary {}, the other services were left alone", unit),
+            ManagerError::LeftoverSyntax { unit, line } => write!(f, "Rendered {} still contains template syntax on line {}, set literal_braces if that's intended", unit, line),
+            ManagerError::MissingSection { unit, section } => write!(f, "Rendered {} has no [{}] section", unit, section),
+            ManagerError::WriteMismatch(path) => write!(f, "{} doesn't contain what was just written to it, the previous file was restored", path.display()),
+            ManagerError::UserUnitDirUnknown => write!(f, "Could not determine the user unit directory, neither XDG_CONFIG_HOME nor HOME is set"),
+            ManagerError::SystemctlFailed { unit, action, code, stderr } => {
+                match code {
+                    Some(code) => write!(f, "systemctl {} failed for {} with exit code {}", action, unit, code)?,
+                    None => write!(f, "systemctl {} failed for {}, terminated by signal", action, unit)?,
+                }
+                if !stderr.trim().is_empty() {
+                    write!(f, ": {}", stderr.trim())?;
+                }
+                Ok(())
+            }
+            ManagerError::VerifyFailed { unit, stderr } => write!(f, "systemd-analyze verify rejected {}: {}", unit, stderr.trim()),
+            ManagerError::RestartFailed { unit, source } => write!(f, "Service {} failed to restart, the previous unit file was restored: {}", unit, source),
+            ManagerError::StateOutOfSync(service) => write!(f, "Service {} has been modified outside of this tool", service),
+            ManagerError::UnsupportedStateVersion(version) => write!(f, "State file version {} is newer than this sdsync supports ({}), upgrade sdsync to use it", version, STATE_VERSION),
+            ManagerError::StateLocked(path) => write!(f, "Another sdsync run is in progress, {} is locked", path.display()),
+            ManagerError::CorruptState { path, reason } => write!(f, "State file {} is corrupt ({}), pass --reset-state to start over with an empty state", path.display(), reason),
+            ManagerError::DuplicateUnit { unit, first, second } => write!(f, "Unit {} is defined in both {} and {}", unit, first, second),
+            ManagerError::DuplicateUnits(units) => write!(f, "Units defined more than once: {}", units.join(", ")),
+            ManagerError::UnknownUnits { unknown, valid } => write!(f, "Unknown units: {}, the known units are: {}", unknown.join(", "), valid.join(", ")),
+            ManagerError::ServicesFailed(errors) => {
+                write!(f, "{} services failed:", errors.len())?;
+                for err in errors {
+                    write!(f, "\n  {}", err)?;
+                }
+                Ok(())
+            }
+            ManagerError::NoBackups(unit) => write!(f, "No backups found for {}", unit),
+            ManagerError::BackupNotFound(path) => write!(f, "Backup not found: {}", path.display()),
+            ManagerError::NothingToUndo(path) => write!(f, "There is no apply to undo, {} doesn't exist", path.display()),
+            ManagerError::UndoModified(files) => write!(f, "Refusing to undo, {} changed since the last apply, pass --force to undo anyway", files.join(", ")),
+            ManagerError::ServiceFailed { unit, source } => write!(f, "{}: {}", unit, source),
+            ManagerError::SystemctlTimedOut { unit, action, timeout } => write!(f, "systemctl {} for {} didn't finish within {}s and was killed", action, unit, timeout),
+            ManagerError::HealthCheckFailed { unit, timeout, output } => {
+                write!(f, "Health check for {} didn't pass within {}s", unit, timeout)?;
+                if !output.trim().is_empty() {
+                    write!(f, ": {}", output.trim())?;
+                }
+                Ok(())
+            }
+            ManagerError::UnknownUser(name) => write!(f, "Unknown user: {}", name),
+            ManagerError::UnknownGroup(name) => write!(f, "Unknown group: {}", name),
+            ManagerError::InvalidUnitName { unit, reason } => write!(f, "Invalid unit name {:?}: {}", unit, reason),
+            ManagerError::InvalidTemplate { unit, reason } => write!(f, "Service {} {}", unit, reason),
+            ManagerError::HookFailed { unit, hook, code, output } => {
+                match code {
+                    Some(code) => write!(f, "{} of {} failed with exit code {}", hook, unit, code)?,
+                    None => write!(f, "{} of {} was terminated by a signal", hook, unit)?,
+                }
+                if !output.trim().is_empty() {
+                    write!(f, ": {}", output.trim())?;
+                }
+                Ok(())
+            }
+            ManagerError::UnusedTemplates(names) => write!(f, "Templates not used by any service: {}", names.join(", ")),
+            ManagerError::NotInSync(units) => write!(f, "Services not in sync: {}", units.join(", ")),
+            ManagerError::DependencyCycle(cycle) => write!(f, "Services depend on each other in a cycle: {}", cycle.join(" -> ")),
+            ManagerError::OutputExists(paths) => {
+                let paths: Vec<String> = paths.iter().map(|path| path.display().to_string()).collect();
+                write!(f, "Refusing to overwrite {}, pass --force to replace them", paths.join(", "))
+            }
+            ManagerError::NotInteractive => write!(f, "Refusing to apply changes without a terminal to confirm them, pass --yes to apply anyway"),
+            ManagerError::TemplateFileFailed { template, service, path, reason } => {
+                write!(f, "Template {}", template)?;
+                if let Some(service) = service {
+                    write!(f, " of {}", service)?;
+                }
+                write!(f, " can't read {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ManagerError {}
+
+// what usually causes a failed write, these are easy to misread in the bare io error
+fn io_hint(source: &io::Error) -> &'static str {
+    match source.kind() {
+        io::ErrorKind::PermissionDenied => ", system units need root",
+        io::ErrorKind::ReadOnlyFilesystem => ", the filesystem is mounted read-only. On an immutable image use a writable --unit-dir such as /run/systemd/system",
+        io::ErrorKind::StorageFull => ", the disk is full. Free up space and run again, nothing was recorded for this file",
+        io::ErrorKind::QuotaExceeded => ", the disk quota is used up",
+        _ => "",
+    }
+}
+
+fn write_failed(path: &Path) -> impl FnOnce(io::Error) -> ManagerError + '_ {
+    move |source| ManagerError::WriteFailed {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+impl ManagerError {
+    // the process exit code for this error, documented in the README so these must not change
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ManagerError::Io(_) => 10,
+            ManagerError::Template(_) => 11,
+            ManagerError::Yaml(_) => 12,
+            ManagerError::Json(_) => 13,
+            ManagerError::Toml(_) => 14,
+            ManagerError::TomlSerialize(_) => 15,
+            
+            ManagerError::TemplateNotFound(_) => 20,
+            ManagerError::IncludeNotFound { .. } => 21,
+            ManagerError::UndefinedVariables { .. } => 22,
+            ManagerError::TemplateFailed { .. } => 23,
+            ManagerError::MissingVariables(_) => 24,
+            ManagerError::MissingEnvVar { .. } => 25,
+            ManagerError::LeftoverSyntax { .. } => 26,
+            ManagerError::MissingSection { .. } => 27,
+            ManagerError::InvalidTemplate { .. } => 28,
+            ManagerError::UnusedTemplates(_) => 29,
+            
+            ManagerError::DuplicateUnit { .. } => 30,
+            ManagerError::DuplicateUnits(_) => 31,
+            ManagerError::UnknownUnits { .. } => 32,
+            ManagerError::InvalidUnitName { .. } => 33,
+            ManagerError::DependencyCycle(_) => 34,
+            ManagerError::UnknownUser(_) => 35,
+            ManagerError::UnknownGroup(_) => 36,
+            
+            ManagerError::UnitDirNotFound(_) => 40,
+            ManagerError::CreateDirFailed { .. } => 41,
+            ManagerError::WriteMismatch(_) => 42,
+            ManagerError::UserUnitDirUnknown => 43,
+            ManagerError::SystemctlFailed { .. } => 44,
+            ManagerError::SystemctlTimedOut { .. } => 45,
+            ManagerError::VerifyFailed { .. } => 46,
+            ManagerError::RestartFailed { .. } => 47,
+            ManagerError::HealthCheckFailed { .. } => 48,
+            ManagerError::HookFailed { .. } => 49,
+            
+            ManagerError::StateOutOfSync(_) => 50,
+            ManagerError::UnsupportedStateVersion(_) => 51,
+            ManagerError::StateLocked(_) => 52,
+            ManagerError::CorruptState { .. } => 53,
+            ManagerError::NotInSync(_) => 54,
+            ManagerError::NoBackups(_) => 55,
+            ManagerError::BackupNotFound(_) => 56,
+            ManagerError::NothingToUndo(_) => 57,
+            ManagerError::UndoModified(_) => 58,
+            
+            ManagerError::NotInteractive => 60,
+            ManagerError::CanaryStopped(_) => 61,
+            ManagerError::ServicesFailed(_) => 62,
+            ManagerError::OutputExists(_) => 63,
+            ManagerError::WriteFailed { .. } => 64,
+            ManagerError::TemplateFileFailed { .. } => 65,
+            // only adds which service failed, what went wrong is the better answer
+            ManagerError::ServiceFailed { source, .. } => source.exit_code(),
+        }
+    }
+}
+
+impl From<std::io::Error> for ManagerError {
+    fn from(err: std::io::Error) -> ManagerError {
+        ManagerError::Io(err)
+    }
+}
+
+impl From<minijinja::Error> for ManagerError {
+    fn from(err: minijinja::Error) -> ManagerError {
+        ManagerError::Template(err)
+    }
+}
+
+impl From<serde_yaml::Error> for ManagerError {
+    fn from(err: serde_yaml::Error) -> ManagerError {
+        ManagerError::Yaml(err)
+    }
+}
+
+impl From<serde_json::Error> for ManagerError {
+    fn from(err: serde_json::Error) -> ManagerError {
+        ManagerError::Json(err)
+    }
+}
+
+impl From<toml::de::Error> for ManagerError {
+    fn from(err: toml::de::Error) -> ManagerError {
+        ManagerError::Toml(err)
+    }
+}
+
+impl From<toml::ser::Error> for ManagerError {
+    fn from(err: toml::ser::Error) -> ManagerError {
+        ManagerError::TomlSerialize(err)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceConfig {
+    /// Template file in the templates directory, empty when `template_inline` is used
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub template: String,
+    /// Template source rendered instead of a file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template_inline: Option<String>,
+    pub unit: String,
+    #[serde(default)]
+    pub variables: BTreeMap<String, serde_yaml::Value>,
+    /// Whether the unit should be enabled on boot, left alone when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    /// Keep the unit masked, which needs no template and removes the file sdsync wrote for it. `false` unmasks it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub masked: Option<bool>,
+    /// How the running service picks up a changed unit file
+    #[serde(default)]
+    pub restart_mode: RestartMode,
+    /// Permissions of the unit file in octal, 0644 when absent
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "deserialize_mode", serialize_with = "serialize_mode")]
+    pub mode: Option<u32>,
+    /// User owning the unit file, root when absent for system units
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Group owning the unit file, root when absent for system units
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Name of a drop-in in `<unit>.d/` to write instead of the unit file, leaving the unit itself alone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dropin: Option<String>,
+    /// Check run after a restart, the previous unit file is restored when it doesn't pass
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
+    /// Units that are synced and restarted before this one, units sdsync doesn't manage are ignored
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+    /// Shell command run before the unit file is written, the change is skipped when it fails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_hook: Option<String>,
+    /// Shell command run after the service restarted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_hook: Option<String>,
+    /// Instances of a template unit like `name@.service`, each rendered to its own `name@<instance>.service`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub instances: Vec<String>,
+    /// File for the unit's `EnvironmentFile=`, rendered and tracked like the unit and restarting it when it changes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment_file: Option<EnvironmentFile>,
+    /// The rendered output is meant to contain `{{`, `{%` or `{#`, so they aren't reported as leftover template syntax
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub literal_braces: bool,
+    /// More files the service reads, any of them changing restarts it like the unit file does
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<ManagedFile>,
+    /// Where the file goes instead of the unit dir, set on the services split off for `environment_file` and `files`
+    #[serde(skip)]
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManagedFile {
+    pub template: String,
+    /// Path of the file, relative ones are in the unit directory. `%i` is replaced with the instance name
+    pub dest: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnvironmentFile {
+    pub template: String,
+    /// Path of the file, relative ones are in the unit directory. `%i` is replaced with the instance name
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthCheck {
+    /// Shell command that exits zero once the service is healthy, `systemctl is-active` when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// Seconds to keep retrying before the check counts as failed
+    #[serde(default = "HealthCheck::default_timeout")]
+    pub timeout: u64,
+    /// Seconds to wait between attempts
+    #[serde(default = "HealthCheck::default_interval")]
+    pub interval: u64,
+}
+
+impl HealthCheck {
+    fn default_timeout() -> u64 {
+        30
+    }
+    
+    fn default_interval() -> u64 {
+        1
+    }
+}
+
+impl ServiceConfig {
+    // masked services only make sure the unit stays masked, they have nothing to render
+    pub fn is_masked(&self) -> bool {
+        self.masked == Some(true)
+    }
+    
+    // the file this service writes, relative to the unit dir
+    pub fn file(&self) -> String {
+        if let Some(path) = &self.path {
+            return path.clone();
+        }
+        match &self.dropin {
+            Some(name) => format!("{}.d/{}.conf", self.unit, name.strip_suffix(".conf").unwrap_or(name)),
+            None => self.unit.clone(),
+        }
+    }
+    
+    // one service per instance of a template unit, with the instance name in the `instance` variable
+    fn instantiate(self) -> Result<Vec<ServiceConfig>, ManagerError> {
+        if self.instances.is_empty() {
+            return Ok(vec![self]);
+        }
+        let Some((prefix, suffix)) = self.unit.split_once("@.") else {
+            return Err(ManagerError::InvalidUnitName {
+                unit: self.unit.clone(),
+                reason: "instances need a template unit such as name@.service",
+            });
+        };
+        
+        let mut services = Vec::with_capacity(self.instances.len());
+        for instance in &self.instances {
+            if instance.is_empty() || instance.contains('/') || instance.contains('@') {
+                return Err(ManagerError::InvalidUnitName {
+                    unit: format!("{}@{}.{}", prefix, instance, suffix),
+                    reason: "instance names must not be empty or contain / or @",
+                });
+            }
+            let mut service = self.clone();
+            service.unit = format!("{}@{}.{}", prefix, instance, suffix);
+            service.instances = Vec::new();
+            if let Some(environment_file) = &mut service.environment_file {
+                environment_file.path = environment_file.path.replace("%i", instance);
+            }
+            for file in &mut service.files {
+                file.dest = file.dest.replace("%i", instance);
+            }
+            service.variables.insert("instance".to_string(), serde_yaml::Value::String(instance.clone()));
+            services.push(service);
+        }
+        Ok(services)
+    }
+    
+    // the service itself followed by one service for its environment file and each of
+    // its other files, which restart the same unit but leave enabling and hooks to the first
+    fn split_files(mut self) -> Vec<ServiceConfig> {
+        let mut extra: Vec<(String, String)> = self.files.drain(..).map(|file| (file.template, file.dest)).collect();
+        if let Some(environment_file) = self.environment_file.take() {
+            extra.insert(0, (environment_file.template, environment_file.path));
+        }
+        
+        let mut services = Vec::with_capacity(extra.len() + 1);
+        for (template, path) in extra {
+            let mut file = self.clone();
+            file.template = template;
+            file.template_inline = None;
+            file.dropin = None;
+            file.path = Some(path);
+            file.enabled = None;
+            file.pre_hook = None;
+            file.post_hook = None;
+            services.push(file);
+        }
+        services.insert(0, self);
+        services
+    }
+}
+
+// the unit a drop-in path from the state belongs to
+fn dropin_unit(file: &str) -> Option<&str> {
+    file.split_once(".d/").map(|(unit, _)| unit)
+}
+
+// only takes a quoted mode such as `"0640"` or `"0o640"`, read as octal like chmod does.
+// YAML and TOML turn `0o640` into the number 416 before it gets here, which can't be
+// told apart from a bare `416`, so numbers are refused rather than guessed at
+fn deserialize_mode<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mode {
+        Text(String),
+        Number(u64),
+    }
+    
+    let text = match Option::<Mode>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(Mode::Text(text)) => text,
+        Some(Mode::Number(number)) => {
+            return Err(serde::de::Error::custom(format!("file mode {} must be quoted, such as \"0644\"", number)));
+        }
+    };
+    let digits = text.strip_prefix("0o").unwrap_or(&text);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(Some(mode)),
+        _ => Err(serde::de::Error::custom(format!("invalid file mode {:?}, expected octal such as \"0644\"", text))),
+    }
+}
+
+fn serialize_mode<S: serde::Serializer>(mode: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error> {
+    match mode {
+        Some(mode) => serializer.serialize_str(&format!("{:04o}", mode)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Mode and ownership applied to a written unit file, `None` leaves the owner as the process' own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilePermissions {
+    pub mode: u32,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FilePermissions {
+    // system units written as root default to root:root like the ones shipped in
+    // packages, user units and runs without root into a unit dir of their own keep
+    // the owner running sdsync, since only root could hand the files to root
+    pub fn resolve(config: &ServiceConfig, user: bool) -> Result<Self, ManagerError> {
+        let default_owner = if user || !nix::unistd::geteuid().is_root() { None } else { Some("root") };
+        let uid = match config.owner.as_deref().or(default_owner) {
+            Some(name) => Some(lookup_user(name)?),
+            None => None,
+        };
+        let gid = match config.group.as_deref().or(default_owner) {
+            Some(name) => Some(lookup_group(name)?),
+            None => None,
+        };
+        Ok(FilePermissions {
+            mode: config.mode.unwrap_or(0o644),
+            uid,
+            gid,
+        })
+    }
+    
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        
+        fs::set_permissions(path, fs::Permissions::from_mode(self.mode))?;
+        // an owner the file already has is left out, so a run without root only chowns when it was asked to
+        let metadata = fs::metadata(path)?;
+        let uid = self.uid.filter(|&uid| uid != metadata.uid());
+        let gid = self.gid.filter(|&gid| gid != metadata.gid());
+        if uid.is_some() || gid.is_some() {
+            std::os::unix::fs::chown(path, uid, gid)?;
+        }
+        Ok(())
+    }
+}
+
+// numeric ids are taken as they are, anything else is looked up by name
+fn lookup_user(name: &str) -> Result<u32, ManagerError> {
+    if let Ok(uid) = name.parse() {
+        return Ok(uid);
+    }
+    match nix::unistd::User::from_name(name) {
+        Ok(Some(user)) => Ok(user.uid.as_raw()),
+        _ => Err(ManagerError::UnknownUser(name.to_string())),
+    }
+}
+
+fn lookup_group(name: &str) -> Result<u32, ManagerError> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    match nix::unistd::Group::from_name(name) {
+        Ok(Some(group)) => Ok(group.gid.as_raw()),
+        _ => Err(ManagerError::UnknownGroup(name.to_string())),
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartMode {
+    #[default]
+    Restart,
+    Reload,
+    ReloadOrRestart,
+    None,
+}
+
+impl RestartMode {
+    pub fn verb(&self) -> Option<&'static str> {
+        match self {
+            RestartMode::Restart => Some("restart"),
+            RestartMode::Reload => Some("reload"),
+            RestartMode::ReloadOrRestart => Some("reload-or-restart"),
+            RestartMode::None => None,
+        }
+    }
+    
+    pub fn description(&self) -> Option<&'static str> {
+        match self {
+            RestartMode::Restart => Some("Restart service"),
+            RestartMode::Reload => Some("Reload service"),
+            RestartMode::ReloadOrRestart => Some("Reload or restart service"),
+            RestartMode::None => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Config {
+    /// Variables available to every service, a service's own variables win on conflicts
+    #[serde(default)]
+    pub variables: BTreeMap<String, serde_yaml::Value>,
+    /// `pre_hook` of the services that don't have their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_hook: Option<String>,
+    /// `post_hook` of the services that don't have their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_hook: Option<String>,
+    pub services: Vec<ServiceConfig>,
+}
+
+impl Config {
+    // moves every service after the ones it depends on, otherwise keeping
+    // the configured order, the units of a cycle are returned as an error
+    fn sort_by_dependencies(&mut self) -> Result<(), ManagerError> {
+        fn visit(
+            index: usize,
+            services: &[ServiceConfig],
+            visited: &mut [bool],
+            path: &mut Vec<usize>,
+            order: &mut Vec<usize>,
+        ) -> Result<(), ManagerError> {
+            if let Some(start) = path.iter().position(|&on_path| on_path == index) {
+                let mut cycle: Vec<String> = path[start..].iter().map(|&i| services[i].file()).collect();
+                cycle.push(services[index].file());
+                return Err(ManagerError::DependencyCycle(cycle));
+            }
+            if visited[index] {
+                return Ok(());
+            }
+            
+            path.push(index);
+            let service = &services[index];
+            for (dependency, other) in services.iter().enumerate() {
+                if other.unit != service.unit && service.depends_on.contains(&other.unit) {
+                    visit(dependency, services, visited, path, order)?;
+                }
+            }
+            path.pop();
+            visited[index] = true;
+            order.push(index);
+            Ok(())
+        }
+        
+        let mut visited = vec![false; self.services.len()];
+        let mut order = Vec::with_capacity(self.services.len());
+        for index in 0..self.services.len() {
+            visit(index, &self.services, &mut visited, &mut Vec::new(), &mut order)?;
+        }
+        
+        let mut services: Vec<Option<ServiceConfig>> = self.services.drain(..).map(Some).collect();
+        self.services = order.into_iter().filter_map(|index| services[index].take()).collect();
+        Ok(())
+    }
+    
+    // names --only and --exclude accept: every written file, and the units
+    // themselves so a unit can be picked together with its drop-ins
+    pub fn units(&self) -> Vec<String> {
+        let mut units: Vec<String> = self.services.iter().map(|service| service.file()).collect();
+        for service in &self.services {
+            if !units.contains(&service.unit) {
+                units.push(service.unit.clone());
+            }
+        }
+        units
+    }
+}
+
+/// Layout version written to new state files, bump it together with a step in `StateFile::migrate`
+pub const STATE_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateFile {
+    pub version: u32,
+    pub services: HashMap<String, ServiceState>,
+    /// Units sdsync masked
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub masked: Vec<String>,
+}
+
+/// What was last applied for a single unit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceState {
+    /// Hash of the rendered unit file as written
+    pub hash: String,
+    /// Algorithm `hash` and `template_hash` were computed with, entries from before it was recorded used sha256
+    #[serde(default)]
+    pub hash_algo: HashAlgo,
+    /// Unit the file belongs to when it is a drop-in or environment file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    /// Template the unit was rendered from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// Hash of the template source at the time it was rendered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template_hash: Option<String>,
+    /// Unix timestamp of the last apply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub applied_at: Option<u64>,
+    /// Enablement last applied for units that have `enabled` set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    /// Rendered unit file as written, only kept with `--store-content`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+impl ServiceState {
+    pub fn new(hash: String, hash_algo: HashAlgo) -> Self {
+        ServiceState {
+            hash,
+            hash_algo,
+            unit: None,
+            template: None,
+            template_hash: None,
+            applied_at: None,
+            enabled: None,
+            content: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ServiceChange {
+    pub unit: String,
+    /// Path of the written file in the unit dir and its key in the state, the unit itself or one of its drop-ins
+    pub file: String,
+    pub template: String,
+    pub template_hash: String,
+    /// Algorithm for the hashes recorded when this change is applied, the one its state entry was written with if it has one
+    pub hash_algo: HashAlgo,
+    pub old_content: Option<String>,
+    pub new_content: String,
+    pub state_modified: bool,
+    /// Whether the template renders differently than what was last applied
+    pub template_changed: bool,
+    /// Whether the template source differs from the one last applied, even when it renders the same
+    pub template_updated: bool,
+    /// Content written by the last apply, when the state stores it
+    pub last_applied_content: Option<String>,
+    /// Enablement to switch to, `None` when it already matches or isn't managed
+    pub enable: Option<bool>,
+    /// Enablement the configuration asks for, recorded in the state even when it already matched
+    pub enabled: Option<bool>,
+    pub restart_mode: RestartMode,
+    pub permissions: FilePermissions,
+    pub health_check: Option<HealthCheck>,
+    pub depends_on: Vec<String>,
+    pub pre_hook: Option<String>,
+    pub post_hook: Option<String>,
+    /// Synced again as is, because an interrupted apply wrote it but may not have restarted it, or with `--force-all`
+    pub rewrite: bool,
+}
+
+impl ServiceChange {
+    pub fn content_changed(&self) -> bool {
+        self.rewrite || self.differs()
+    }
+    
+    // whether there is anything to show in a diff
+    pub fn differs(&self) -> bool {
+        self.old_content.as_deref() != Some(self.new_content.as_str())
+    }
+}
+
+impl Default for StateFile {
+    fn default() -> Self {
+        StateFile {
+            version: STATE_VERSION,
+            services: HashMap::new(),
+            masked: Vec::new(),
+        }
+    }
+}
+
+impl StateFile {
+    pub fn load_or_create(path: &Path, files: &dyn FileSystem) -> Result<Self, ManagerError> {
+        if !files.exists(path) {
+            return Ok(StateFile::default());
+        }
+        
+        log::info!("Loading state {}", path.display());
+        let reason = match StateFile::parse(&files.read_to_string(path)?) {
+            Err(ManagerError::Yaml(err)) => err.to_string(),
+            result => return result,
+        };
+        
+        // fall back to the copy kept by the previous save, it may be a run
+        // behind but that beats losing track of every unit
+        let backup = StateFile::backup_path(path);
+        if files.exists(&backup) {
+            if let Ok(state) = StateFile::parse(&files.read_to_string(&backup)?) {
+                log::warn!("State file {} is corrupt ({}), using {} instead", path.display(), reason, backup.display());
+                return Ok(state);
+            }
+        }
+        Err(ManagerError::CorruptState { path: path.to_path_buf(), reason })
+    }
+    
+    /// The state file of one host in a directory shared by several, `<dir>/<name>.yaml` with the hostname as the default name
+    pub fn path_in(dir: &Path, name: Option<&str>) -> Result<PathBuf, ManagerError> {
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => nix::unistd::gethostname().map_err(io::Error::from)?.to_string_lossy().into_owned(),
+        };
+        Ok(dir.join(format!("{}.yaml", name)))
+    }
+    
+    /// Where the previous state is kept, `<state>.bak`
+    pub fn backup_path(path: &Path) -> PathBuf {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(".bak");
+        PathBuf::from(backup)
+    }
+    
+    fn parse(content: &str) -> Result<Self, ManagerError> {
+        let value: serde_yaml::Value = serde_yaml::from_str(content)?;
+        StateFile::migrate(value)
+    }
+
+    // upgrades an on-disk state of any older version to the current layout,
+    // files from before versioning have no `version` and count as version 0
+    fn migrate(mut value: serde_yaml::Value) -> Result<Self, ManagerError> {
+        let version = value
+            .get("version")
+            .and_then(serde_yaml::Value::as_u64)
+            .unwrap_or(0);
+        if version > u64::from(STATE_VERSION) {
+            return Err(ManagerError::UnsupportedStateVersion(version));
+        }
+        
+        // version 1 only added the version field itself, so there is nothing
+        // to convert for version 0 beyond stamping the current version below
+        
+        // version 2 turned the plain hash per unit into an entry and folded
+        // the separate `enabled` map into it
+        if version < 2 {
+            if let Some(mapping) = value.as_mapping_mut() {
+                let enabled = mapping.remove("enabled");
+                if let Some(serde_yaml::Value::Mapping(services)) = mapping.get_mut("services") {
+                    for (unit, entry) in services.iter_mut() {
+                        if let serde_yaml::Value::String(hash) = entry {
+                            let mut upgraded = serde_yaml::Mapping::new();
+                            upgraded.insert("hash".into(), hash.clone().into());
+                            if let Some(unit_enabled) = enabled.as_ref().and_then(|enabled| enabled.get(unit)) {
+                                upgraded.insert("enabled".into(), unit_enabled.clone());
+                            }
+                            *entry = serde_yaml::Value::Mapping(upgraded);
+                        }
+                    }
+                }
+            }
+        }
+        
+        if let Some(mapping) = value.as_mapping_mut() {
+            mapping.insert("version".into(), STATE_VERSION.into());
+        }
+        
+        Ok(serde_yaml::from_value(value)?)
+    }
+
+    pub fn save(&self, path: &Path, files: &dyn FileSystem) -> Result<(), ManagerError> {
+        let content = serde_yaml::to_string(self)?;
+        
+        // keep the previous state around, unless it is the corrupt file a
+        // load fell back from and would clobber the good backup
+        if let Ok(previous) = files.read_to_string(path) {
+            if StateFile::parse(&previous).is_ok() {
+                let backup = StateFile::backup_path(path);
+                files.copy(path, &backup).map_err(write_failed(&backup))?;
+            }
+        }
+        files.write(path, &content, None).map_err(write_failed(path))
+    }
+
+    pub fn validate_service(&self, unit: &str, content: &str) -> bool {
+        match self.services.get(unit) {
+            Some(entry) => {
+                let hash = calculate_hash(content, entry.hash_algo);
+                log::debug!("Comparing {:?} hash of {}: state {}, unit file {}", entry.hash_algo, unit, entry.hash, hash);
+                hash == entry.hash
+            }
+            None => {
+                log::debug!("No state for {}, nothing to compare", unit);
+                true
+            }
+        }
+    }
+}
+
+/// Exclusive lock on `<state>.lock` held for the duration of a run, released on drop
+pub struct StateLock {
+    _guard: Box<dyn Send>,
+}
+
+impl StateLock {
+    pub fn acquire(state_path: &Path, timeout: std::time::Duration, files: &dyn FileSystem) -> Result<Self, ManagerError> {
+        // lock a sidecar file, the state file itself gets replaced when saving
+        let mut lock_path = state_path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+        
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            match files.try_lock(&lock_path)? {
+                Some(guard) => return Ok(StateLock { _guard: guard }),
+                None if std::time::Instant::now() < deadline => {
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                }
+                None => return Err(ManagerError::StateLocked(lock_path)),
+            }
+        }
+    }
+}
+
+/// Unit files an apply is about to write, kept in `<state>.journal` until it finishes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Journal {
+    /// Unix timestamp of the apply that wrote the journal
+    pub started_at: u64,
+    pub changes: Vec<JournalEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub file: String,
+    /// Hash of the content the file is about to be written with
+    pub hash: String,
+    pub hash_algo: HashAlgo,
+}
+
+impl Journal {
+    pub fn new(plan: &Plan) -> Journal {
+        Journal {
+            started_at: unix_timestamp(),
+            changes: plan.changes
+                .iter()
+                .filter(|change| change.content_changed())
+                .map(|change| JournalEntry {
+                    file: change.file.clone(),
+                    hash: calculate_hash(&change.new_content, change.hash_algo),
+                    hash_algo: change.hash_algo,
+                })
+                .collect(),
+        }
+    }
+    
+    pub fn path(state_path: &Path) -> PathBuf {
+        let mut path = state_path.as_os_str().to_owned();
+        path.push(".journal");
+        PathBuf::from(path)
+    }
+    
+    /// The journal a run that was killed before it finished left behind
+    pub fn load(path: &Path, files: &dyn FileSystem) -> Result<Option<Journal>, ManagerError> {
+        match files.read_to_string(path) {
+            Ok(content) => Ok(Some(serde_yaml::from_str(&content)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+    
+    pub fn save(&self, path: &Path, files: &dyn FileSystem) -> Result<(), ManagerError> {
+        files.write(path, &serde_yaml::to_string(self)?, None).map_err(write_failed(path))
+    }
+    
+    pub fn remove(path: &Path, files: &dyn FileSystem) -> Result<(), ManagerError> {
+        match files.remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+    
+    // whether the interrupted run got as far as writing this content to the file
+    fn wrote(&self, file: &str, content: &str) -> bool {
+        self.changes
+            .iter()
+            .any(|entry| entry.file == file && entry.hash == calculate_hash(content, entry.hash_algo))
+    }
+}
+
+/// What the most recent apply changed and what was there before, kept in
+/// `<state>.last-run` so `undo` can put it all back
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LastRun {
+    /// Unix timestamp of the apply
+    pub applied_at: u64,
+    pub changes: Vec<RunEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RunEntry {
+    pub file: String,
+    /// Unit the file belongs to, the file itself unless it is a drop-in or environment file
+    pub unit: String,
+    /// Hash of the file as the apply left it, absent when it removed the file
+    pub hash: Option<String>,
+    pub hash_algo: HashAlgo,
+    /// The file before the apply, absent when the apply created it
+    pub previous_content: Option<String>,
+    /// Mode and ownership of the file before the apply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<FilePermissions>,
+    /// Enablement the apply switched the unit to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    /// The state entry before the apply, absent when the file wasn't tracked yet
+    pub previous_state: Option<ServiceState>,
+}
+
+impl RunEntry {
+    /// Whether the apply wrote or removed the file, rather than only enabling or disabling its unit
+    pub fn content_changed(&self) -> bool {
+        self.previous_content.as_deref().map(|content| calculate_hash(content, self.hash_algo)) != self.hash
+    }
+}
+
+impl LastRun {
+    /// Snapshots the files a plan is about to write or remove and the units it
+    /// enables or disables, taken before it is applied
+    pub fn new(plan: &Plan, state: &StateFile, unit_dir: &Path, files: &dyn FileSystem) -> LastRun {
+        let mut changes: Vec<RunEntry> = plan.changes
+            .iter()
+            .map(|change| RunEntry {
+                file: change.file.clone(),
+                unit: change.unit.clone(),
+                hash: Some(calculate_hash(&change.new_content, change.hash_algo)),
+                hash_algo: change.hash_algo,
+                previous_content: change.old_content.clone(),
+                permissions: files.permissions(&unit_dir.join(&change.file)).ok(),
+                enabled: change.enable,
+                previous_state: state.services.get(&change.file).cloned(),
+            })
+            .collect();
+        for file in &plan.removals {
+            let previous_state = state.services.get(file).cloned();
+            let unit = previous_state.as_ref().and_then(|entry| entry.unit.as_deref()).or(dropin_unit(file)).unwrap_or(file);
+            changes.push(RunEntry {
+                file: file.clone(),
+                unit: unit.to_string(),
+                hash: None,
+                hash_algo: previous_state.as_ref().map(|entry| entry.hash_algo).unwrap_or_default(),
+                previous_content: files.read_to_string(&unit_dir.join(file)).ok(),
+                permissions: files.permissions(&unit_dir.join(file)).ok(),
+                enabled: None,
+                previous_state,
+            });
+        }
+        LastRun {
+            applied_at: unix_timestamp(),
+            changes,
+        }
+    }
+    
+    /// Keeps only what the apply actually got through, going by the state it left
+    pub fn finish(mut self, state: &StateFile) -> LastRun {
+        self.changes.retain(|entry| match (&entry.hash, state.services.get(&entry.file)) {
+            (Some(hash), Some(current)) => &current.hash == hash && (entry.enabled.is_none() || current.enabled == entry.enabled),
+            (Some(_), None) => false,
+            (None, current) => current.is_none(),
+        });
+        self
+    }
+    
+    pub fn path(state_path: &Path) -> PathBuf {
+        let mut path = state_path.as_os_str().to_owned();
+        path.push(".last-run");
+        PathBuf::from(path)
+    }
+    
+    pub fn load(path: &Path, files: &dyn FileSystem) -> Result<Option<LastRun>, ManagerError> {
+        match files.read_to_string(path) {
+            Ok(content) => Ok(Some(serde_yaml::from_str(&content)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+    
+    pub fn save(&self, path: &Path, files: &dyn FileSystem) -> Result<(), ManagerError> {
+        files.write(path, &serde_yaml::to_string(self)?, None).map_err(write_failed(path))
+    }
+    
+    pub fn remove(path: &Path, files: &dyn FileSystem) -> Result<(), ManagerError> {
+        match files.remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+    
+    /// Files that no longer are the way the apply left them
+    pub fn modified(&self, unit_dir: &Path, files: &dyn FileSystem) -> Vec<String> {
+        self.changes
+            .iter()
+            .filter(|entry| {
+                let current = files.read_to_string(&unit_dir.join(&entry.file)).ok();
+                current.map(|content| calculate_hash(&content, entry.hash_algo)) != entry.hash
+            })
+            .map(|entry| entry.file.clone())
+            .collect()
+    }
+}
+
+/// Puts every file of the last apply back the way it was, reloads once and
+/// restarts the units involved, then rolls their state entries back
+pub fn undo_run(
+    run: &LastRun,
+    unit_dir: &Path,
+    files: &dyn FileSystem,
+    runner: &dyn CommandRunner,
+    user: bool,
+    state: &mut StateFile,
+    progress: &dyn Fn(&str),
+) -> Result<(), ManagerError> {
+    for entry in run.changes.iter().rev().filter(|entry| entry.content_changed()) {
+        let path = unit_dir.join(&entry.file);
+        match &entry.previous_content {
+            Some(content) => {
+                progress(&format!("Restoring {}", entry.file));
+                if let Some(parent) = path.parent() {
+                    files.create_dir_all(parent)?;
+                }
+                files.write(&path, content, entry.permissions.as_ref()).map_err(write_failed(&path))?;
+            }
+            // the file was already gone when the apply removed it from the state
+            None if entry.hash.is_none() => {}
+            None => {
+                progress(&format!("Removing {}", entry.file));
+                if entry.file == entry.unit {
+                    run_systemctl(runner, user, &entry.unit, &["stop", &entry.unit])?;
+                    run_systemctl(runner, user, &entry.unit, &["disable", &entry.unit])?;
+                }
+                if files.exists(&path) {
+                    files.remove_file(&path)?;
+                }
+                if entry.file.starts_with(&format!("{}.d/", entry.unit)) {
+                    if let Some(dropin_dir) = path.parent() {
+                        let _ = files.remove_dir(dropin_dir);
+                    }
+                }
+            }
+        }
+    }
+    
+    let files_changed: Vec<&str> = run.changes
+        .iter()
+        .filter(|entry| entry.content_changed())
+        .map(|entry| entry.file.as_str())
+        .collect();
+    if !files_changed.is_empty() {
+        run_systemctl(runner, user, &files_changed.join(", "), &["daemon-reload"])?;
+    }
+    
+    // in the order the apply went, which puts dependencies first
+    let mut restarted: Vec<&str> = Vec::new();
+    for entry in &run.changes {
+        // a unit the apply created was already stopped and disabled when it was removed
+        if entry.previous_content.is_none() {
+            continue;
+        }
+        if entry.content_changed() && !restarted.contains(&entry.unit.as_str()) {
+            if entry.file != entry.unit {
+                // a stopped unit stays stopped, a running one picks up its drop-ins as they were
+                run_systemctl(runner, user, &entry.unit, &["try-restart", &entry.unit])?;
+            } else {
+                run_systemctl(runner, user, &entry.unit, &["restart", &entry.unit])?;
+            }
+            restarted.push(&entry.unit);
+        }
+        // a removal disabled the unit, otherwise switch back what the apply switched
+        let enabled = match entry.hash {
+            None => entry.previous_state.as_ref().and_then(|previous| previous.enabled),
+            Some(_) => entry.enabled.map(|enabled| !enabled),
+        };
+        match enabled {
+            Some(true) => run_systemctl(runner, user, &entry.unit, &["enable", &entry.unit])?,
+            Some(false) => run_systemctl(runner, user, &entry.unit, &["disable", &entry.unit])?,
+            None => {}
+        }
+    }
+    
+    for entry in &run.changes {
+        match &entry.previous_state {
+            Some(previous) => state.services.insert(entry.file.clone(), previous.clone()),
+            None => state.services.remove(&entry.file),
+        };
+    }
+    Ok(())
+}
+
+pub fn default_unit_dir(user: bool) -> Result<PathBuf, ManagerError> {
+    if !user {
+        return Ok(PathBuf::from("/etc/systemd/system"));
+    }
+    
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match std::env::var_os("HOME") {
+            Some(home) if !home.is_empty() => PathBuf::from(home).join(".config"),
+            _ => return Err(ManagerError::UserUnitDirUnknown),
+        },
+    };
+    Ok(config_home.join("systemd").join("user"))
+}
+
+static SYSTEMCTL_PATH: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+
+/// Runs `path` instead of the `systemctl` found on `PATH`, only the first call has an effect
+pub fn set_systemctl_path(path: PathBuf) {
+    let _ = SYSTEMCTL_PATH.set(path);
+}
+
+/// What a command printed and how it exited
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    /// Exit code, `None` when it was terminated by a signal
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// Runs the programs sdsync drives: systemctl, systemd-analyze and the hook and health check commands
+pub trait CommandRunner: Sync {
+    /// Runs `program` with `args` and the extra `env` and waits for it to exit. Once
+    /// `timeout` runs out it is killed and an error of kind `TimedOut` returned.
+    fn run(
+        &self,
+        program: &Path,
+        args: &[&str],
+        env: &[(&str, &str)],
+        timeout: Option<std::time::Duration>,
+    ) -> io::Result<CommandOutput>;
+}
+
+/// Runs commands as child processes
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(
+        &self,
+        program: &Path,
+        args: &[&str],
+        env: &[(&str, &str)],
+        timeout: Option<std::time::Duration>,
+    ) -> io::Result<CommandOutput> {
+        let mut child = std::process::Command::new(program)
+            .args(args)
+            .envs(env.iter().copied())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        if let Some(timeout) = timeout {
+            let deadline = std::time::Instant::now() + timeout;
+            while child.try_wait()?.is_none() {
+                if std::time::Instant::now() >= deadline {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+        }
+        let output = child.wait_with_output()?;
+        Ok(CommandOutput {
+            code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+}
+
+/// Records the commands it is asked to run instead of running them, for testing code that drives systemctl
+#[derive(Default)]
+pub struct RecordingRunner {
+    calls: std::sync::Mutex<Vec<String>>,
+    responses: std::sync::Mutex<Vec<(String, CommandOutput)>>,
+}
+
+impl RecordingRunner {
+    pub fn new() -> Self {
+        RecordingRunner::default()
+    }
+    
+    /// Answers commands starting with `prefix` with `output`, anything else exits zero without output
+    pub fn respond(&self, prefix: &str, output: CommandOutput) {
+        self.responses.lock().expect("responses poisoned").push((prefix.to_string(), output));
+    }
+    
+    /// The commands run so far in order, each as its program and arguments joined by spaces
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().expect("calls poisoned").clone()
+    }
+}
+
+impl CommandRunner for RecordingRunner {
+    fn run(
+        &self,
+        program: &Path,
+        args: &[&str],
+        _env: &[(&str, &str)],
+        _timeout: Option<std::time::Duration>,
+    ) -> io::Result<CommandOutput> {
+        let mut call = program.display().to_string();
+        for arg in args {
+            call.push(' ');
+            call.push_str(arg);
+        }
+        let output = self.responses
+            .lock()
+            .expect("responses poisoned")
+            .iter()
+            .find(|(prefix, _)| call.starts_with(prefix.as_str()))
+            .map_or_else(|| CommandOutput { code: Some(0), ..CommandOutput::default() }, |(_, output)| output.clone());
+        self.calls.lock().expect("calls poisoned").push(call);
+        Ok(output)
+    }
+}
+
+fn systemctl(
+    runner: &dyn CommandRunner,
+    user: bool,
+    args: &[&str],
+    timeout: Option<std::time::Duration>,
+) -> io::Result<CommandOutput> {
+    let path = SYSTEMCTL_PATH.get().map_or(Path::new("systemctl"), PathBuf::as_path);
+    let mut full_args = Vec::with_capacity(args.len() + 1);
+    if user {
+        full_args.push("--user");
+    }
+    full_args.extend_from_slice(args);
+    runner.run(path, &full_args, &[], timeout)
+}
+
+/// Access to the unit and backup directories, so syncing can run against something other than the disk
+pub trait FileSystem: Sync {
+    fn read_to_string(&self, path: &Path) -> io::Result<String>;
+    fn exists(&self, path: &Path) -> bool;
+    fn is_dir(&self, path: &Path) -> bool;
+    /// Replaces `path` with `content` in one step, with `permissions` already applied
+    fn write(&self, path: &Path, content: &str, permissions: Option<&FilePermissions>) -> io::Result<()>;
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Mode and ownership of an existing file
+    fn permissions(&self, path: &Path) -> io::Result<FilePermissions>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    /// Removes an empty directory
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+    /// Paths of the files and directories directly inside `path`
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+    /// Adds `content` to the end of `path`, creating it when it's missing
+    fn append(&self, path: &Path, content: &str) -> io::Result<()>;
+    /// The path with symlinks resolved, an error when it doesn't exist
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+    /// Takes an exclusive lock on `path`, creating the file, `None` while someone else holds it.
+    /// The lock is released when the returned guard is dropped
+    fn try_lock(&self, path: &Path) -> io::Result<Option<Box<dyn Send>>>;
+}
+
+/// The real filesystem
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+    
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+    
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+    
+    fn write(&self, path: &Path, content: &str, permissions: Option<&FilePermissions>) -> io::Result<()> {
+        write_atomic(path, content, permissions)
+    }
+    
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::copy(from, to).map(|_| ())
+    }
+    
+    fn permissions(&self, path: &Path) -> io::Result<FilePermissions> {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = fs::metadata(path)?;
+        Ok(FilePermissions {
+            mode: metadata.mode() & 0o7777,
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+        })
+    }
+    
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+    
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+    
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+    
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?.map(|entry| Ok(entry?.path())).collect()
+    }
+    
+    fn append(&self, path: &Path, content: &str) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(content.as_bytes())
+    }
+    
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+    
+    fn try_lock(&self, path: &Path) -> io::Result<Option<Box<dyn Send>>> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(Box::new(file))),
+            Err(fs::TryLockError::WouldBlock) => Ok(None),
+            Err(fs::TryLockError::Error(err)) => Err(err),
+        }
+    }
+}
+
+/// A tree of files kept in memory, for testing syncs without touching the disk
+#[derive(Default)]
+pub struct MemoryFileSystem {
+    files: std::sync::Mutex<std::collections::BTreeMap<PathBuf, String>>,
+    dirs: std::sync::Mutex<std::collections::BTreeSet<PathBuf>>,
+    permissions: std::sync::Mutex<std::collections::BTreeMap<PathBuf, FilePermissions>>,
+    locks: std::sync::Arc<std::sync::Mutex<std::collections::BTreeSet<PathBuf>>>,
+}
+
+// a lock taken on a MemoryFileSystem, given up again when dropped
+struct MemoryLock {
+    locks: std::sync::Arc<std::sync::Mutex<std::collections::BTreeSet<PathBuf>>>,
+    path: PathBuf,
+}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        self.locks.lock().expect("locks poisoned").remove(&self.path);
+    }
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        MemoryFileSystem::default()
+    }
+    
+    /// Adds a file, creating the directories it is in
+    pub fn insert(&self, path: impl Into<PathBuf>, content: impl Into<String>) {
+        let path = path.into();
+        if let Some(parent) = path.parent() {
+            let _ = self.create_dir_all(parent);
+        }
+        self.files.lock().expect("files poisoned").insert(path, content.into());
+    }
+    
+    /// Content of a file, `None` when it doesn't exist
+    pub fn get(&self, path: &Path) -> Option<String> {
+        self.files.lock().expect("files poisoned").get(path).cloned()
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        self.get(path).ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+    
+    fn exists(&self, path: &Path) -> bool {
+        self.get(path).is_some() || self.is_dir(path)
+    }
+    
+    fn is_dir(&self, path: &Path) -> bool {
+        self.dirs.lock().expect("dirs poisoned").contains(path)
+    }
+    
+    fn write(&self, path: &Path, content: &str, permissions: Option<&FilePermissions>) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() && !self.is_dir(parent) => Err(io::ErrorKind::NotFound.into()),
+            _ => {
+                self.files.lock().expect("files poisoned").insert(path.to_path_buf(), content.to_string());
+                let mut stored = self.permissions.lock().expect("permissions poisoned");
+                match permissions {
+                    Some(permissions) => stored.insert(path.to_path_buf(), *permissions),
+                    None => stored.remove(path),
+                };
+                Ok(())
+            }
+        }
+    }
+    
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let content = self.read_to_string(from)?;
+        let permissions = self.permissions(from)?;
+        self.write(to, &content, Some(&permissions))
+    }
+    
+    // files written without permissions get what a 022 umask gives
+    fn permissions(&self, path: &Path) -> io::Result<FilePermissions> {
+        if self.get(path).is_none() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let stored = self.permissions.lock().expect("permissions poisoned").get(path).copied();
+        Ok(stored.unwrap_or(FilePermissions { mode: 0o644, uid: None, gid: None }))
+    }
+    
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match self.files.lock().expect("files poisoned").remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+    
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut dirs = self.dirs.lock().expect("dirs poisoned");
+        for dir in path.ancestors().filter(|dir| !dir.as_os_str().is_empty()) {
+            dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+    
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let files = self.files.lock().expect("files poisoned");
+        let mut dirs = self.dirs.lock().expect("dirs poisoned");
+        let in_use = files.keys().any(|file| file.starts_with(path))
+            || dirs.iter().any(|dir| dir != path && dir.starts_with(path));
+        if in_use {
+            return Err(io::ErrorKind::DirectoryNotEmpty.into());
+        }
+        if dirs.remove(path) {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::NotFound.into())
+        }
+    }
+    
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        if !self.is_dir(path) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let files = self.files.lock().expect("files poisoned");
+        let dirs = self.dirs.lock().expect("dirs poisoned");
+        Ok(files.keys().chain(dirs.iter()).filter(|entry| entry.parent() == Some(path)).cloned().collect())
+    }
+    
+    fn append(&self, path: &Path, content: &str) -> io::Result<()> {
+        let existing = self.get(path).unwrap_or_default();
+        self.write(path, &(existing + content), None)
+    }
+    
+    // there are no symlinks in memory
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        if self.exists(path) {
+            Ok(path.to_path_buf())
+        } else {
+            Err(io::ErrorKind::NotFound.into())
+        }
+    }
+    
+    fn try_lock(&self, path: &Path) -> io::Result<Option<Box<dyn Send>>> {
+        if !self.exists(path) {
+            self.write(path, "", None)?;
+        }
+        if !self.locks.lock().expect("locks poisoned").insert(path.to_path_buf()) {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(MemoryLock {
+            locks: self.locks.clone(),
+            path: path.to_path_buf(),
+        })))
+    }
+}
+
+/// Reads through to another file system and leaves every change out, for dry runs
+pub struct DryRunFileSystem<'a> {
+    pub inner: &'a dyn FileSystem,
+}
+
+impl FileSystem for DryRunFileSystem<'_> {
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        self.inner.read_to_string(path)
+    }
+    
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+    
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.is_dir(path)
+    }
+    
+    fn write(&self, path: &Path, _content: &str, _permissions: Option<&FilePermissions>) -> io::Result<()> {
+        log::debug!("Dry run, not writing {}", path.display());
+        Ok(())
+    }
+    
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        log::debug!("Dry run, not copying {} to {}", from.display(), to.display());
+        Ok(())
+    }
+    
+    fn permissions(&self, path: &Path) -> io::Result<FilePermissions> {
+        self.inner.permissions(path)
+    }
+    
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        log::debug!("Dry run, not removing {}", path.display());
+        Ok(())
+    }
+    
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        log::debug!("Dry run, not creating {}", path.display());
+        Ok(())
+    }
+    
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        log::debug!("Dry run, not removing {}", path.display());
+        Ok(())
+    }
+    
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.read_dir(path)
+    }
+    
+    fn append(&self, path: &Path, _content: &str) -> io::Result<()> {
+        log::debug!("Dry run, not appending to {}", path.display());
+        Ok(())
+    }
+    
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.inner.canonicalize(path)
+    }
+    
+    // a dry run still waits for an apply that is running, so what it reports isn't half written
+    fn try_lock(&self, path: &Path) -> io::Result<Option<Box<dyn Send>>> {
+        self.inner.try_lock(path)
+    }
+}
+
+// writes to a temp file next to the target and renames it over, so the target
+// is never left half written or briefly with the wrong permissions
+pub fn write_atomic(path: &Path, content: &str, permissions: Option<&FilePermissions>) -> io::Result<()> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!("{}.tmp-{}", file_name, std::process::id()));
+    
+    let result = (|| {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        if let Some(permissions) = permissions {
+            permissions.apply(&tmp_path)?;
+        }
+        
+        match fs::rename(&tmp_path, path) {
+            Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+                fs::copy(&tmp_path, path)?;
+                fs::remove_file(&tmp_path)
+            }
+            result => result,
+        }
+    })();
+    
+    if result.is_err() && tmp_path.exists() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+// runs systemctl and turns a non-zero exit into an error carrying its stderr
+pub fn run_systemctl(runner: &dyn CommandRunner, user: bool, unit: &str, args: &[&str]) -> Result<(), ManagerError> {
+    run_systemctl_timeout(runner, user, unit, args, None)
+}
+
+// like `run_systemctl`, but kills systemctl once the timeout runs out
+pub fn run_systemctl_timeout(
+    runner: &dyn CommandRunner,
+    user: bool,
+    unit: &str,
+    args: &[&str],
+    timeout: Option<std::time::Duration>,
+) -> Result<(), ManagerError> {
+    log::debug!("Running systemctl {}", args.join(" "));
+    let output = match systemctl(runner, user, args, timeout) {
+        Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+            return Err(ManagerError::SystemctlTimedOut {
+                unit: unit.to_string(),
+                action: args.join(" "),
+                timeout: timeout.map_or(0, |timeout| timeout.as_secs()),
+            });
+        }
+        result => result?,
+    };
+    log::debug!("systemctl {} exited with {:?}", args.join(" "), output.code);
+    if output.success() {
+        return Ok(());
+    }
+    
+    Err(ManagerError::SystemctlFailed {
+        unit: unit.to_string(),
+        action: args.join(" "),
+        code: output.code,
+        stderr: output.stderr,
+    })
+}
+
+// runs `systemd-analyze verify` on the rendered unit before it gets anywhere near the unit dir
+pub fn verify_unit(change: &ServiceChange, files: &dyn FileSystem, runner: &dyn CommandRunner, user: bool) -> Result<(), ManagerError> {
+    // a drop-in or environment file on its own isn't a unit systemd-analyze can load
+    if change.file != change.unit {
+        log::info!("Not verifying {}, it isn't a unit file", change.file);
+        return Ok(());
+    }
+    
+    // the file has to keep the unit name so systemd knows what kind of unit it is
+    let verify_dir = std::env::temp_dir().join(format!("sdsync-verify-{}", std::process::id()));
+    files.create_dir_all(&verify_dir)?;
+    let unit_path = verify_dir.join(&change.unit);
+    
+    let result = (|| {
+        files.write(&unit_path, &change.new_content, None)?;
+        let unit_path = unit_path.to_string_lossy();
+        let args: &[&str] = if user { &["--user", "verify", &unit_path] } else { &["verify", &unit_path] };
+        log::debug!("Running systemd-analyze verify {}", unit_path);
+        Ok::<_, ManagerError>(runner.run(Path::new("systemd-analyze"), args, &[], None)?)
+    })();
+    let _ = files.remove_file(&unit_path);
+    let _ = files.remove_dir(&verify_dir);
+    let output = result?;
+    log::debug!("systemd-analyze verify exited with {:?}", output.code);
+    
+    // problems with other units (missing dependencies and such) aren't ours to report
+    let unit_path = unit_path.to_string_lossy();
+    let reported = output.stderr.lines().any(|line| line.contains(unit_path.as_ref()));
+    if output.success() && !reported {
+        return Ok(());
+    }
+    
+    Err(ManagerError::VerifyFailed {
+        unit: change.unit.clone(),
+        stderr: output.stderr,
+    })
+}
+
+fn is_active(runner: &dyn CommandRunner, user: bool, unit: &str) -> Result<bool, ManagerError> {
+    // is-active exits non-zero for anything but active, the state printed tells starting units apart
+    let output = systemctl(runner, user, &["is-active", unit], None)?;
+    let active = output.stdout.trim();
+    log::debug!("systemctl is-active {} printed {}", unit, active);
+    Ok(matches!(active, "active" | "activating" | "reloading"))
+}
+
+fn is_masked(runner: &dyn CommandRunner, user: bool, unit: &str) -> Result<bool, ManagerError> {
+    let output = systemctl(runner, user, &["is-enabled", unit], None)?;
+    Ok(output.stdout.trim() == "masked")
+}
+
+// `None` for units `systemctl enable` and `disable` don't apply to, like static or generated ones
+fn is_enabled(runner: &dyn CommandRunner, user: bool, unit: &str) -> Result<Option<bool>, ManagerError> {
+    // is-enabled exits non-zero for disabled or unknown units, we only care about the state it prints
+    let output = systemctl(runner, user, &["is-enabled", unit], None)?;
+    log::debug!("systemctl is-enabled {} exited with {:?}", unit, output.code);
+    Ok(match output.stdout.trim() {
+        "enabled" | "enabled-runtime" => Some(true),
+        "static" | "alias" | "indirect" | "generated" | "transient" => None,
+        _ => Some(false),
+    })
+}
+
+// copies the current unit file into the backup dir as `<unit>.<unix timestamp>`
+pub fn backup_unit(files: &dyn FileSystem, service_path: &Path, unit: &str, backup_dir: &Path) -> Result<PathBuf, ManagerError> {
+    let backup_path = backup_path(files, unit, backup_dir)?;
+    files.copy(service_path, &backup_path).map_err(write_failed(&backup_path))?;
+    Ok(backup_path)
+}
+
+// the same, for a unit file whose content was already read while planning. It
+// gets the unit's permissions, a secrets file mustn't become readable in its backup
+fn backup_content(
+    files: &dyn FileSystem,
+    content: &str,
+    permissions: &FilePermissions,
+    unit: &str,
+    backup_dir: &Path,
+) -> Result<PathBuf, ManagerError> {
+    let backup_path = backup_path(files, unit, backup_dir)?;
+    files.write(&backup_path, content, Some(permissions)).map_err(write_failed(&backup_path))?;
+    Ok(backup_path)
+}
+
+fn backup_path(files: &dyn FileSystem, unit: &str, backup_dir: &Path) -> Result<PathBuf, ManagerError> {
+    // an environment file outside the unit dir is backed up under its path in the backup dir
+    let name = format!("{}.{}", unit.trim_start_matches('/'), unix_timestamp());
+    let mut backup_path = backup_dir.join(&name);
+    // a second backup within the same second gets a counter instead of replacing the first
+    let mut counter = 1;
+    while files.exists(&backup_path) {
+        backup_path = backup_dir.join(format!("{}-{}", name, counter));
+        counter += 1;
+    }
+    
+    // drop-ins keep their `<unit>.d/` directory in the backups
+    create_dir(files, backup_path.parent().unwrap_or(backup_dir))?;
+    Ok(backup_path)
+}
+
+// timestamps of the backups for a unit, oldest first. Backups taken within the
+// same second are told apart by a counter, as in `<unix timestamp>-1`
+pub fn list_backups(files: &dyn FileSystem, unit: &str, backup_dir: &Path) -> Result<Vec<String>, ManagerError> {
+    if !files.is_dir(backup_dir) {
+        return Ok(Vec::new());
+    }
+    
+    let prefix = format!("{}.", unit);
+    let mut timestamps: Vec<((u64, u64), String)> = Vec::new();
+    for entry in files.read_dir(backup_dir)? {
+        let name = entry.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        if let Some(order) = name.strip_prefix(&prefix).and_then(backup_order) {
+            timestamps.push((order, name[prefix.len()..].to_string()));
+        }
+    }
+    timestamps.sort();
+    Ok(timestamps.into_iter().map(|(_, timestamp)| timestamp).collect())
+}
+
+// the timestamp and counter of a backup suffix, None for anything else
+fn backup_order(suffix: &str) -> Option<(u64, u64)> {
+    let number = |part: &str| -> Option<u64> {
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        part.parse().ok()
+    };
+    match suffix.split_once('-') {
+        Some((timestamp, counter)) => Some((number(timestamp)?, number(counter)?)),
+        None => Some((number(suffix)?, 0)),
+    }
+}
+
+// the backup of `unit` taken at `timestamp`, which has to be one `list_backups` finds
+// so a timestamp such as `../../etc/x` can't name a file outside the backup dir
+pub fn find_backup(files: &dyn FileSystem, unit: &str, backup_dir: &Path, timestamp: &str) -> Result<PathBuf, ManagerError> {
+    let backup_path = backup_dir.join(format!("{}.{}", unit, timestamp));
+    if !list_backups(files, unit, backup_dir)?.iter().any(|backup| backup == timestamp) {
+        return Err(ManagerError::BackupNotFound(backup_path));
+    }
+    Ok(backup_path)
+}
+
+pub fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Hashes `content` the way the state records unit files, as lowercase hex. Hashing a
+/// rendered unit with the state's `hash_algo` gives the hash a sync stores for it
+pub fn calculate_hash(content: &str, algo: HashAlgo) -> String {
+    match algo {
+        HashAlgo::Sha256 => format!("{:x}", Sha256::digest(content.as_bytes())),
+        HashAlgo::Sha512 => format!("{:x}", Sha512::digest(content.as_bytes())),
+        HashAlgo::Blake3 => blake3::hash(content.as_bytes()).to_hex().to_string(),
+    }
+}
+
+// functions minijinja provides out of the box and `file`, they show up as undeclared names in templates
+const BUILTIN_GLOBALS: &[&str] = &["range", "dict", "debug", "namespace", "file"];
+
+// top-level names a template reads that it doesn't define itself
+fn template_variables(template: &minijinja::Template) -> Vec<String> {
+    let mut names: Vec<String> = template
+        .undeclared_variables(false)
+        .into_iter()
+        .filter(|name| !BUILTIN_GLOBALS.contains(&name.as_str()))
+        .collect();
+    names.sort();
+    names
+}
+
+// checks up front that every service sets all the variables its template reads,
+// so all the gaps are reported at once instead of one failed render at a time
+pub fn check_variables(
+    services: &[&ServiceConfig],
+    global_variables: &BTreeMap<String, serde_yaml::Value>,
+    secrets: &BTreeMap<String, serde_yaml::Value>,
+    overrides: &[VariableOverride],
+    templates: &Templates,
+) -> Result<(), ManagerError> {
+    let mut missing: Vec<(String, Vec<String>)> = Vec::new();
+    for service in services.iter().filter(|service| !service.is_masked()) {
+        let template = match &service.template_inline {
+            Some(source) => templates.env.template_from_named_str(INLINE_TEMPLATE, source).map_err(ManagerError::Template),
+            None => templates.load(&service.template),
+        }
+        .map_err(|err| match err {
+            ManagerError::Template(source) => template_error(templates, service, source),
+            err => err,
+        })?;
+        let names: Vec<String> = template_variables(&template)
+            .into_iter()
+            .filter(|name| {
+                !service.variables.contains_key(name)
+                    && !global_variables.contains_key(name)
+                    && !secrets.contains_key(name)
+                    && !overrides.iter().any(|entry| entry.key == *name && entry.applies_to(service))
+            })
+            .collect();
+        if !names.is_empty() {
+            missing.push((service.unit.clone(), names));
+        }
+    }
+    
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(ManagerError::MissingVariables(missing))
+    }
+}
+
+// names of the templates a template pulls in with include, extends, import or from,
+// and of the files it reads with `file`. Only the code between `{{ }}` and `{% %}` is
+// looked at, so comments, plain text and string literals mentioning them don't count
+fn referenced_templates(source: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    for (tag, code) in template_code(source) {
+        let tokens = code_tokens(code);
+        if tag && matches!(tokens.first(), Some(Token::Ident("include" | "extends" | "import" | "from"))) {
+            // every quoted string in the tag, so lists of fallbacks count as well
+            names.extend(tokens.iter().filter_map(|token| match token {
+                Token::Str(name) => Some(name.to_string()),
+                _ => None,
+            }));
+            continue;
+        }
+        
+        // files read with `file(...)` are in the templates dir unless --files-dir says otherwise
+        for (index, window) in tokens.windows(3).enumerate() {
+            let after_dot = index > 0 && tokens[index - 1] == Token::Punct('.');
+            if let [Token::Ident("file"), Token::Punct('('), Token::Str(name)] = window {
+                if !after_dot {
+                    names.push(name.to_string());
+                }
+            }
+        }
+    }
+    names
+}
+
+// the expressions and tags of a template, true for a `{% %}` tag, without their
+// delimiters and whitespace control
+fn template_code(source: &str) -> Vec<(bool, &str)> {
+    let mut code = Vec::new();
+    let mut rest = source;
+    while let Some(start) = rest.find('{') {
+        let close = match rest[start + 1..].chars().next() {
+            Some('{') => "}}",
+            Some('%') => "%}",
+            Some('#') => "#}",
+            _ => {
+                rest = &rest[start + 1..];
+                continue;
+            }
+        };
+        let tag = close == "%}";
+        rest = &rest[start + 2..];
+        let end = rest.find(close).unwrap_or(rest.len());
+        if close != "#}" {
+            code.push((tag, rest[..end].trim_start_matches(['-', '+']).trim_end_matches(['-', '+'])));
+        }
+        rest = &rest[(end + 2).min(rest.len())..];
+    }
+    code
+}
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Ident(&'a str),
+    Str(&'a str),
+    Punct(char),
+}
+
+// splits template code into identifiers, string literals and single characters
+fn code_tokens(code: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut chars = code.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c == '"' || c == '\'' {
+            let mut end = code.len();
+            while let Some((index, next)) = chars.next() {
+                if next == '\\' {
+                    chars.next();
+                } else if next == c {
+                    end = index;
+                    break;
+                }
+            }
+            tokens.push(Token::Str(&code[start + 1..end]));
+        } else if c.is_alphanumeric() || c == '_' {
+            let mut end = code.len();
+            while let Some(&(index, next)) = chars.peek() {
+                if !(next.is_alphanumeric() || next == '_') {
+                    end = index;
+                    break;
+                }
+                chars.next();
+            }
+            tokens.push(Token::Ident(&code[start..end]));
+        } else {
+            tokens.push(Token::Punct(c));
+        }
+    }
+    tokens
+}
+
+// every file in the template dir no service renders, directly or through an
+// include, as names relative to the template dir
+pub fn unused_templates(config: &Config, template_dir: &Path, files: &dyn FileSystem) -> Result<Vec<String>, ManagerError> {
+    if !files.is_dir(template_dir) {
+        return Ok(Vec::new());
+    }
+    
+    let mut templates: Vec<String> = Vec::new();
+    let mut dirs = vec![template_dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for path in files.read_dir(&dir)? {
+            if files.is_dir(&path) {
+                dirs.push(path);
+            } else if let Ok(relative) = path.strip_prefix(template_dir) {
+                let name: Vec<String> = relative.components().map(|part| part.as_os_str().to_string_lossy().into_owned()).collect();
+                templates.push(name.join("/"));
+            }
+        }
+    }
+    
+    let mut used: Vec<String> = Vec::new();
+    let mut pending: Vec<String> = Vec::new();
+    for service in &config.services {
+        match &service.template_inline {
+            Some(source) => pending.extend(referenced_templates(source)),
+            None => pending.push(service.template.clone()),
+        }
+    }
+    while let Some(name) = pending.pop() {
+        if used.contains(&name) {
+            continue;
+        }
+        if let Ok(source) = files.read_to_string(&template_dir.join(&name)) {
+            pending.extend(referenced_templates(&source));
+        }
+        used.push(name);
+    }
+    
+    let mut unused: Vec<String> = templates.into_iter().filter(|name| !used.contains(name)).collect();
+    unused.sort();
+    Ok(unused)
+}
+
+// the name errors and includes see for a `template_inline`
+const INLINE_TEMPLATE: &str = "template_inline";
+
+// the same escaping as `systemd-escape`, for putting arbitrary text into unit and instance names
+fn systemd_escape(value: String) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for (index, byte) in value.bytes().enumerate() {
+        match byte {
+            b'/' => escaped.push('-'),
+            b'.' if index == 0 => escaped.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+// single quotes the value for a shell unless it's made of characters that never need it
+fn quote(value: String) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-.,:/=+@%".contains(c);
+    if !value.is_empty() && value.chars().all(safe) {
+        value
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+// `yes` or `no` from booleans, numbers and the usual spellings of them in strings
+fn bool(value: minijinja::Value) -> Result<String, minijinja::Error> {
+    let truthy = match value.as_str() {
+        Some(text) => match text.trim().to_ascii_lowercase().as_str() {
+            "yes" | "y" | "true" | "on" | "1" => true,
+            "no" | "n" | "false" | "off" | "0" | "" => false,
+            _ => return Err(minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                format!("{:?} is not a boolean", text),
+            )),
+        },
+        None => value.is_true(),
+    };
+    Ok(if truthy { "yes" } else { "no" }.to_string())
+}
+
+// what went wrong in `file`, carried through minijinja so it can become a ManagerError
+#[derive(Debug)]
+struct FileReadError {
+    path: PathBuf,
+    reason: String,
+}
+
+impl fmt::Display for FileReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "can't read {}: {}", self.path.display(), self.reason)
+    }
+}
+
+impl std::error::Error for FileReadError {}
+
+// `file("path")`, the contents of a file under `base`. Absolute paths, `..` and
+// symlinks pointing out of it are refused so templates can't read anything else
+fn read_file(files: &dyn FileSystem, base: &Path, name: &str) -> Result<String, minijinja::Error> {
+    let relative = Path::new(name);
+    let path = base.join(relative);
+    let fail = |path: PathBuf, reason: String| {
+        let err = FileReadError { path, reason };
+        minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, err.to_string()).with_source(err)
+    };
+    let outside = format!("only files inside {} can be read", base.display());
+    if relative.components().any(|part| !matches!(part, Component::Normal(_) | Component::CurDir)) {
+        return Err(fail(path, outside));
+    }
+    match files.canonicalize(&path).and_then(|resolved| Ok(resolved.starts_with(files.canonicalize(base)?))) {
+        Ok(true) => files.read_to_string(&path).map_err(|err| fail(path, err.to_string())),
+        Ok(false) => Err(fail(path, outside)),
+        Err(err) => Err(fail(path, err.to_string())),
+    }
+}
+
+// the file systems templates are read through, shared with the functions minijinja keeps
+type SharedFileSystem = std::sync::Arc<dyn FileSystem + Send>;
+
+// like minijinja's path_loader, names with a segment starting with `.` are never found
+fn load_template(files: &dyn FileSystem, template_dir: &Path, name: &str) -> Result<Option<String>, minijinja::Error> {
+    let mut path = template_dir.to_path_buf();
+    for segment in name.split('/') {
+        if segment.starts_with('.') || segment.contains('\\') {
+            return Ok(None);
+        }
+        path.push(segment);
+    }
+    match files.read_to_string(&path) {
+        Ok(source) => Ok(Some(source)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, "could not read template").with_source(err)),
+    }
+}
+
+// load through the templates dir so includes and imports resolve relative to it
+fn template_env(template_dir: &Path, files_dir: &Path, files: &SharedFileSystem) -> Environment<'static> {
+    let mut env = Environment::new();
+    let (loader_files, loader_dir) = (files.clone(), template_dir.to_path_buf());
+    env.set_loader(move |name| load_template(loader_files.as_ref(), &loader_dir, name));
+    env.add_filter("systemd_escape", systemd_escape);
+    env.add_filter("quote", quote);
+    env.add_filter("bool", bool);
+    let (function_files, base) = (files.clone(), files_dir.to_path_buf());
+    env.add_function("file", move |name: String| read_file(function_files.as_ref(), &base, &name));
+    env
+}
+
+// points a minijinja error at the template file it came from, which may be an include
+fn template_error(templates: &Templates, service: &ServiceConfig, err: minijinja::Error) -> ManagerError {
+    let (path, source) = match &service.template_inline {
+        Some(source) if err.name() == Some(INLINE_TEMPLATE) => (PathBuf::from(INLINE_TEMPLATE), Some(source.clone())),
+        _ => {
+            let name = err.name().unwrap_or_default();
+            // minijinja keeps the source of every template it parsed, even one that failed to render
+            let source = templates.env.get_template(name).ok().map(|template| template.source().to_string());
+            (templates.dir().join(name), source)
+        }
+    };
+    // minijinja only keeps the byte range of the failing expression, so count back to its line start
+    let column = err.range().zip(source).and_then(|(range, source)| {
+        let before = source.get(..range.start)?;
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+        Some(before[line_start..].chars().count() + 1)
+    });
+    ManagerError::TemplateFailed {
+        service: service.unit.clone(),
+        path,
+        line: err.line(),
+        column,
+        source: err,
+    }
+}
+
+// one template environment for a whole run, minijinja keeps every template it
+// loaded so services sharing a template (or an include) only read and parse it once
+pub struct Templates {
+    env: Environment<'static>,
+    template_dir: PathBuf,
+    files_dir: PathBuf,
+    files: SharedFileSystem,
+    strict: bool,
+}
+
+impl Templates {
+    pub fn new(template_dir: &Path, strict: bool) -> Self {
+        Templates::build(template_dir.to_path_buf(), template_dir.to_path_buf(), std::sync::Arc::new(OsFileSystem), strict)
+    }
+    
+    fn build(template_dir: PathBuf, files_dir: PathBuf, files: SharedFileSystem, strict: bool) -> Self {
+        let mut env = template_env(&template_dir, &files_dir, &files);
+        if strict {
+            env.set_undefined_behavior(minijinja::UndefinedBehavior::Strict);
+        }
+        Templates {
+            env,
+            template_dir,
+            files_dir,
+            files,
+            strict,
+        }
+    }
+    
+    // reads `file(...)` from `files_dir` instead of the templates dir
+    pub fn with_files_dir(self, files_dir: &Path) -> Self {
+        Templates::build(self.template_dir, files_dir.to_path_buf(), self.files, self.strict)
+    }
+    
+    /// Reads the templates and their files through `files` instead of the disk
+    pub fn with_file_system(self, files: std::sync::Arc<dyn FileSystem + Send>) -> Self {
+        Templates::build(self.template_dir, self.files_dir, files, self.strict)
+    }
+    
+    pub fn dir(&self) -> &Path {
+        &self.template_dir
+    }
+    
+    // loads the template of every service up front, which also surfaces syntax errors before anything is rendered
+    pub fn warm(&self, services: &[&ServiceConfig]) -> Result<(), ManagerError> {
+        for service in services {
+            if service.template_inline.is_some() || service.is_masked() {
+                continue;
+            }
+            self.load(&service.template).map_err(|err| match err {
+                ManagerError::Template(source) => template_error(self, service, source),
+                err => err,
+            })?;
+        }
+        Ok(())
+    }
+    
+    fn load(&self, template_name: &str) -> Result<minijinja::Template<'_, '_>, ManagerError> {
+        let template_path = self.template_dir.join(template_name);
+        if !self.files.exists(&template_path) {
+            return Err(ManagerError::TemplateNotFound(template_path));
+        }
+        Ok(self.env.get_template(template_name)?)
+    }
+    
+    // the source of a template file, as it was loaded
+    pub fn source(&self, template_name: &str) -> Result<String, ManagerError> {
+        Ok(self.load(template_name)?.source().to_string())
+    }
+    
+    pub fn render(&self, template_name: &str, variables: &BTreeMap<String, serde_yaml::Value>) -> Result<String, ManagerError> {
+        log::debug!("Rendering template {}", self.template_dir.join(template_name).display());
+        let template = self.load(template_name)?;
+        render_loaded(&template, &self.template_dir, variables, self.strict)
+    }
+    
+    // renders a `template_inline` source, includes still come from the templates dir
+    pub fn render_inline(&self, source: &str, variables: &BTreeMap<String, serde_yaml::Value>) -> Result<String, ManagerError> {
+        let template = self.env.template_from_named_str(INLINE_TEMPLATE, source)?;
+        render_loaded(&template, &self.template_dir, variables, self.strict)
+    }
+}
+
+/// Renders `template_name` from `template_dir` with exactly the given variables, with the
+/// filters and includes a sync uses. `strict` makes undefined variables an error. For what
+/// a sync writes for a service, merged variables and all, use [`render_service`]
+pub fn render_template(
+    template_dir: &Path,
+    template_name: &str,
+    variables: &BTreeMap<String, serde_yaml::Value>,
+    strict: bool,
+) -> Result<String, ManagerError> {
+    Templates::new(template_dir, strict).render(template_name, variables)
+}
+
+/// Like [`render_template`] for a template given as a string, includes are still read from `template_dir`
+pub fn render_inline(
+    template_dir: &Path,
+    source: &str,
+    variables: &BTreeMap<String, serde_yaml::Value>,
+    strict: bool,
+) -> Result<String, ManagerError> {
+    Templates::new(template_dir, strict).render_inline(source, variables)
+}
+
+fn render_loaded(
+    template: &minijinja::Template,
+    template_dir: &Path,
+    variables: &BTreeMap<String, serde_yaml::Value>,
+    strict: bool,
+) -> Result<String, ManagerError> {
+    let template_name = template.name();
+    template.render(variables).map_err(|err| {
+        let file = std::error::Error::source(&err).and_then(|source| source.downcast_ref::<FileReadError>());
+        if let Some(file) = file {
+            return ManagerError::TemplateFileFailed {
+                template: template_name.to_string(),
+                service: None,
+                path: file.path.clone(),
+                reason: file.reason.clone(),
+            };
+        }
+        match err.kind() {
+            minijinja::ErrorKind::TemplateNotFound => ManagerError::IncludeNotFound {
+                template: template_name.to_string(),
+                template_dir: template_dir.to_path_buf(),
+                detail: err.detail().unwrap_or_default().to_string(),
+            },
+            // minijinja doesn't say which name was undefined, so work out which ones weren't given
+            minijinja::ErrorKind::UndefinedError if strict => ManagerError::UndefinedVariables {
+                template: template_name.to_string(),
+                service: None,
+                names: template_variables(template)
+                    .into_iter()
+                    .filter(|name| !variables.contains_key(name))
+                    .collect(),
+                source: err,
+            },
+            _ => ManagerError::Template(err),
+        }
+    })
+}
+
+// a `--set [unit:]key=value` from the command line, the value is always a string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VariableOverride {
+    /// Unit or written file the override is limited to, every service when absent
+    pub unit: Option<String>,
+    pub key: String,
+    pub value: String,
+}
+
+impl VariableOverride {
+    pub fn applies_to(&self, service: &ServiceConfig) -> bool {
+        self.unit.as_ref().is_none_or(|unit| *unit == service.unit || *unit == service.file())
+    }
+}
+
+impl FromStr for VariableOverride {
+    type Err = String;
+    
+    fn from_str(text: &str) -> Result<VariableOverride, String> {
+        let (name, value) = text.split_once('=').ok_or_else(|| format!("expected [UNIT:]KEY=VALUE, got {}", text))?;
+        let (unit, key) = match name.split_once(':') {
+            Some((unit, key)) => (Some(unit.to_string()), key),
+            None => (None, name),
+        };
+        if key.is_empty() || unit.as_ref().is_some_and(|unit| unit.is_empty()) {
+            return Err(format!("expected [UNIT:]KEY=VALUE, got {}", text));
+        }
+        Ok(VariableOverride {
+            unit,
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+// settings for rendering that are the same for every service
+#[derive(Clone, Copy)]
+pub struct RenderOptions<'a> {
+    /// Shared by every service, and strict about undefined variables with `--strict-vars`
+    pub templates: &'a Templates,
+    pub expand_env: bool,
+    /// Values from `--secrets`, merged over the configured variables
+    pub secrets: &'a BTreeMap<String, serde_yaml::Value>,
+    /// Values from `--set`, merged over everything else
+    pub overrides: &'a [VariableOverride],
+    /// Fail on template syntax left in the rendered output instead of warning
+    pub strict_syntax: bool,
+    /// Fail on unit files missing the section for their type instead of warning
+    pub strict_sections: bool,
+}
+
+// settings for computing changes that are the same for every service
+#[derive(Clone, Copy)]
+pub struct PreviewOptions<'a> {
+    pub render: RenderOptions<'a>,
+    pub unit_dir: &'a Path,
+    pub files: &'a dyn FileSystem,
+    pub runner: &'a dyn CommandRunner,
+    pub user: bool,
+    pub hash_algo: HashAlgo,
+}
+
+// replaces `${NAME}` with the value of the environment variable, `$$` is a literal `$`
+fn expand_env_str(value: &str, service: &str, key: &str) -> Result<String, ManagerError> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            expanded.push('$');
+            rest = after;
+        } else if let Some((name, after)) = rest.strip_prefix("${").and_then(|inner| inner.split_once('}')) {
+            match std::env::var(name) {
+                Ok(env_value) => expanded.push_str(&env_value),
+                Err(_) => return Err(ManagerError::MissingEnvVar {
+                    name: name.to_string(),
+                    service: service.to_string(),
+                    variable: key.to_string(),
+                }),
+            }
+            rest = after;
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn expand_env_value(value: &mut serde_yaml::Value, service: &str, key: &str) -> Result<(), ManagerError> {
+    match value {
+        serde_yaml::Value::String(text) => *text = expand_env_str(text, service, key)?,
+        serde_yaml::Value::Sequence(items) => {
+            for item in items {
+                expand_env_value(item, service, key)?;
+            }
+        }
+        serde_yaml::Value::Mapping(mapping) => {
+            for (_, item) in mapping.iter_mut() {
+                expand_env_value(item, service, key)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+pub fn load_secrets(path: &Path, files: &dyn FileSystem) -> Result<BTreeMap<String, serde_yaml::Value>, ManagerError> {
+    // json is valid yaml, so this reads both
+    let content = files.read_to_string(path)?;
+    Ok(serde_yaml::from_str(&content)?)
+}
+
+// every scalar in the values as it would show up in a rendered unit
+fn scalar_values<'a>(values: impl Iterator<Item = &'a serde_yaml::Value>) -> Vec<String> {
+    fn collect(value: &serde_yaml::Value, values: &mut Vec<String>) {
+        match value {
+            serde_yaml::Value::String(text) => values.push(text.clone()),
+            serde_yaml::Value::Number(number) => values.push(number.to_string()),
+            serde_yaml::Value::Sequence(items) => items.iter().for_each(|item| collect(item, values)),
+            serde_yaml::Value::Mapping(mapping) => mapping.values().for_each(|item| collect(item, values)),
+            _ => {}
+        }
+    }
+    
+    let mut scalars = Vec::new();
+    values.for_each(|value| collect(value, &mut scalars));
+    scalars.retain(|value| !value.is_empty());
+    // mask longer values first so a secret containing another one is hidden entirely
+    scalars.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    scalars.dedup();
+    scalars
+}
+
+// every scalar in the secrets as it would show up in a rendered unit
+pub fn secret_values(secrets: &BTreeMap<String, serde_yaml::Value>) -> Vec<String> {
+    scalar_values(secrets.values())
+}
+
+/// The values to mask in diffs: the secrets, and the variables named in `redact`
+/// as they resolve for each service, after `${NAME}` expansion and `--set`
+pub fn masked_values(config: &Config, redact: &[String], options: &RenderOptions) -> Result<Vec<String>, ManagerError> {
+    let mut values: Vec<serde_yaml::Value> = options.secrets.values().cloned().collect();
+    let mut found = BTreeSet::new();
+    for service in config.services.iter().filter(|service| !service.is_masked()) {
+        let variables = service_variables(service, &config.variables, options)?;
+        for key in redact {
+            if let Some(value) = variables.get(key) {
+                found.insert(key.as_str());
+                values.push(value.clone());
+            }
+        }
+    }
+    for key in redact.iter().filter(|key| !found.contains(key.as_str())) {
+        log::warn!("No service has a variable named {} to redact", key);
+    }
+    Ok(scalar_values(values.iter()))
+}
+
+// the variables a service is rendered with, later sources winning
+fn service_variables(
+    config: &ServiceConfig,
+    global_variables: &BTreeMap<String, serde_yaml::Value>,
+    options: &RenderOptions,
+) -> Result<BTreeMap<String, serde_yaml::Value>, ManagerError> {
+    let mut variables = global_variables.clone();
+    variables.extend(config.variables.iter().map(|(key, value)| (key.clone(), value.clone())));
+    if options.expand_env {
+        for (key, value) in variables.iter_mut() {
+            expand_env_value(value, &config.unit, key)?;
+        }
+    }
+    variables.extend(options.secrets.iter().map(|(key, value)| (key.clone(), value.clone())));
+    for entry in options.overrides.iter().filter(|entry| entry.applies_to(config)) {
+        variables.insert(entry.key.clone(), serde_yaml::Value::String(entry.value.clone()));
+    }
+    Ok(variables)
+}
+
+/// Merges the variables for a service and renders its template, which is the content a sync writes
+pub fn render_service(
+    config: &ServiceConfig,
+    global_variables: &BTreeMap<String, serde_yaml::Value>,
+    options: &RenderOptions,
+) -> Result<String, ManagerError> {
+    let variables = service_variables(config, global_variables, options)?;
+    
+    let rendered = match &config.template_inline {
+        Some(source) => options.templates.render_inline(source, &variables),
+        None => options.templates.render(&config.template, &variables),
+    };
+    let rendered = rendered.map_err(|err| match err {
+        ManagerError::Template(source) => template_error(options.templates, config, source),
+        ManagerError::UndefinedVariables { template, names, source, .. } => ManagerError::UndefinedVariables {
+            template,
+            service: Some(config.unit.clone()),
+            names,
+            source,
+        },
+        ManagerError::TemplateFileFailed { template, path, reason, .. } => ManagerError::TemplateFileFailed {
+            template,
+            service: Some(config.unit.clone()),
+            path,
+            reason,
+        },
+        err => err,
+    })?;
+    
+    // usually a typo in a tag that minijinja passed through as text
+    if !config.literal_braces {
+        if let Some(line) = template_syntax_line(&rendered) {
+            let err = ManagerError::LeftoverSyntax {
+                unit: config.file(),
+                line,
+            };
+            if options.strict_syntax {
+                return Err(err);
+            }
+            log::warn!("{}", err);
+        }
+    }
+    
+    // drop-ins and other files only hold part of a unit, so only unit files are checked
+    if config.file() == config.unit {
+        if let Some(section) = missing_section(&config.unit, &rendered) {
+            let err = ManagerError::MissingSection {
+                unit: config.unit.clone(),
+                section,
+            };
+            if options.strict_sections {
+                return Err(err);
+            }
+            log::warn!("{}", err);
+        }
+    }
+    Ok(rendered)
+}
+
+// the section a unit of this type can't do without, `[Unit]` for types that have none of their own
+fn missing_section(unit: &str, content: &str) -> Option<&'static str> {
+    let section = match unit.rsplit_once('.').map(|(_, suffix)| suffix) {
+        Some("service") => "Service",
+        Some("socket") => "Socket",
+        Some("mount") => "Mount",
+        Some("automount") => "Automount",
+        Some("swap") => "Swap",
+        Some("path") => "Path",
+        Some("timer") => "Timer",
+        _ => "Unit",
+    };
+    let header = format!("[{}]", section);
+    if content.lines().any(|line| line.trim() == header) {
+        None
+    } else {
+        Some(section)
+    }
+}
+
+// the first line with a `{{`, `{%` or `{#` in it, counting from 1
+pub fn template_syntax_line(content: &str) -> Option<usize> {
+    content
+        .lines()
+        .position(|line| line.contains("{{") || line.contains("{%") || line.contains("{#"))
+        .map(|index| index + 1)
+}
+
+pub fn preview_changes(
+    config: &ServiceConfig,
+    global_variables: &BTreeMap<String, serde_yaml::Value>,
+    options: &PreviewOptions,
+    state: &StateFile,
+) -> Result<ServiceChange, ManagerError> {
+    let PreviewOptions { render, unit_dir, files, runner, user, hash_algo } = *options;
+    
+    let new_content = render_service(config, global_variables, &render)?;
+    let template_source = match &config.template_inline {
+        Some(source) => source.clone(),
+        None => render.templates.source(&config.template)?,
+    };
+    let file = config.file();
+    let service_path = unit_dir.join(&file);
+    // an entry already in the state keeps the algorithm it was written with
+    let applied = state.services.get(&file);
+    let hash_algo = applied.map(|entry| entry.hash_algo).unwrap_or(hash_algo);
+    let template_hash = calculate_hash(&template_source, hash_algo);
+    
+    let (old_content, mut state_modified) = if files.exists(&service_path) {
+        let content = files.read_to_string(&service_path)?;
+        let valid = state.validate_service(&file, &content);
+        (Some(content), !valid)
+    } else {
+        (None, false)
+    };
+    
+    let template_changed = applied.is_some_and(|entry| entry.hash != calculate_hash(&new_content, entry.hash_algo));
+    // entries written by a rollback or from before template hashes were kept have nothing to compare
+    let template_updated = applied
+        .and_then(|entry| entry.template_hash.as_ref().map(|hash| *hash != calculate_hash(&template_source, entry.hash_algo)))
+        .unwrap_or(false);
+    let last_applied_content = applied.and_then(|entry| entry.content.clone());
+    
+    let mut enable = None;
+    if let Some(desired) = config.enabled {
+        match is_enabled(runner, user, &config.unit)? {
+            Some(current) => {
+                if let Some(applied_enabled) = applied.and_then(|entry| entry.enabled) {
+                    state_modified |= applied_enabled != current;
+                }
+                if current != desired {
+                    enable = Some(desired);
+                }
+            }
+            None => log::info!("{} can't be enabled or disabled, leaving its `enabled` alone", config.unit),
+        }
+    }
+    
+    Ok(ServiceChange {
+        unit: config.unit.clone(),
+        file,
+        template: config.template.clone(),
+        template_hash,
+        hash_algo,
+        old_content,
+        new_content,
+        state_modified,
+        template_changed,
+        template_updated,
+        last_applied_content,
+        enable,
+        enabled: config.enabled,
+        restart_mode: config.restart_mode,
+        permissions: FilePermissions::resolve(config, user)?,
+        health_check: config.health_check.clone(),
+        depends_on: config.depends_on.clone(),
+        pre_hook: config.pre_hook.clone(),
+        post_hook: config.post_hook.clone(),
+        rewrite: false,
+    })
+}
+
+// writes the new unit file, returning what it replaced and where that was backed up to
+fn write_unit(change: &ServiceChange, options: &SyncOptions) -> Result<(Option<String>, Option<PathBuf>), ManagerError> {
+    let SyncOptions { unit_dir, backup_dir, files, .. } = *options;
+    let service_path = unit_dir.join(&change.file);
+    // planning already read what's on disk, no need to read it again
+    let previous_content = change.old_content.clone();
+    let backup_path = match &previous_content {
+        Some(content) => Some(backup_content(files, content, &change.permissions, &change.file, backup_dir)?),
+        None => None,
+    };
+    
+    if change.file != change.unit {
+        create_dir(files, service_path.parent().unwrap_or(unit_dir))?;
+    }
+    files.write(&service_path, &change.new_content, Some(&change.permissions)).map_err(write_failed(&service_path))?;
+    
+    // read it back so the hash recorded in the state is known to match what's on disk
+    let written = files.read_to_string(&service_path)?;
+    if calculate_hash(&written, change.hash_algo) != calculate_hash(&change.new_content, change.hash_algo) {
+        restore_unit(change, options, previous_content.as_deref())?;
+        return Err(ManagerError::WriteMismatch(service_path));
+    }
+    Ok((previous_content, backup_path))
+}
+
+fn create_dir(files: &dyn FileSystem, path: &Path) -> Result<(), ManagerError> {
+    files.create_dir_all(path).map_err(|source| ManagerError::CreateDirFailed {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+// puts back whatever was there before so we don't leave a broken unit behind
+fn restore_unit(change: &ServiceChange, options: &SyncOptions, previous_content: Option<&str>) -> Result<(), ManagerError> {
+    let service_path = options.unit_dir.join(&change.file);
+    match previous_content {
+        Some(content) => options.files.write(&service_path, content, Some(&change.permissions)).map_err(write_failed(&service_path))?,
+        None => options.files.remove_file(&service_path)?,
+    }
+    Ok(())
+}
+
+// returns whether the service was restarted at all
+fn restart_unit(change: &ServiceChange, options: &SyncOptions) -> Result<bool, ManagerError> {
+    restart_with_mode(options.runner, options.user, &change.unit, change.restart_mode, options.start_inactive, options.restart_timeout)
+}
+
+/// Restarts a service the way its restart mode asks for, only when it is running unless
+/// `start_inactive` is set. Returns whether it was restarted at all
+pub fn restart_with_mode(
+    runner: &dyn CommandRunner,
+    user: bool,
+    unit: &str,
+    restart_mode: RestartMode,
+    start_inactive: bool,
+    timeout: Option<std::time::Duration>,
+) -> Result<bool, ManagerError> {
+    let Some(verb) = restart_mode.verb() else {
+        return Ok(false);
+    };
+    if !start_inactive && !is_active(runner, user, unit)? {
+        log::info!("Not restarting {}, it isn't running", unit);
+        return Ok(false);
+    }
+    run_systemctl_timeout(runner, user, unit, &[verb, unit], timeout)?;
+    Ok(true)
+}
+
+// polls the health check until it passes or its timeout runs out
+fn check_health(change: &ServiceChange, check: &HealthCheck, options: &SyncOptions) -> Result<(), ManagerError> {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(check.timeout);
+    loop {
+        // a check that hangs is killed once the timeout is up and counts as a failed attempt
+        let remaining = Some(deadline.saturating_duration_since(std::time::Instant::now()));
+        let output = match &check.command {
+            Some(command) => options.runner.run(Path::new("sh"), &["-c", command], &[], remaining),
+            None => systemctl(options.runner, options.user, &["is-active", &change.unit], remaining),
+        };
+        let output = match output {
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => CommandOutput {
+                code: None,
+                stdout: String::new(),
+                stderr: format!("health check timed out after {}s", check.timeout),
+            },
+            output => output?,
+        };
+        log::debug!("Health check for {} exited with {:?}", change.unit, output.code);
+        if output.success() {
+            return Ok(());
+        }
+        
+        if std::time::Instant::now() >= deadline {
+            return Err(ManagerError::HealthCheckFailed {
+                unit: change.unit.clone(),
+                timeout: check.timeout,
+                output: output.stderr + &output.stdout,
+            });
+        }
+        std::thread::sleep(std::time::Duration::from_secs(check.interval));
+    }
+}
+
+// restarts the service and waits for it to pass its health check, if it has
+// one, with `--wait-active` a service without one waits to become active
+fn restart_checked(change: &ServiceChange, options: &SyncOptions) -> Result<(), ManagerError> {
+    if !restart_unit(change, options)? {
+        return Ok(());
+    }
+    match &change.health_check {
+        Some(check) => check_health(change, check, options),
+        None if options.wait_active => {
+            let check = HealthCheck {
+                command: None,
+                timeout: options.restart_timeout.map_or(HealthCheck::default_timeout(), |timeout| timeout.as_secs()),
+                interval: HealthCheck::default_interval(),
+            };
+            check_health(change, &check, options)
+        }
+        None => Ok(()),
+    }
+}
+
+fn enable_unit(change: &ServiceChange, options: &SyncOptions) -> Result<(), ManagerError> {
+    match change.enable {
+        Some(true) => run_systemctl(options.runner, options.user, &change.unit, &["enable", &change.unit]),
+        Some(false) => run_systemctl(options.runner, options.user, &change.unit, &["disable", &change.unit]),
+        None => Ok(()),
+    }
+}
+
+fn record_service(change: &ServiceChange, options: &SyncOptions, state: &mut StateFile) {
+    audit_change(change, options);
+    let previous_enabled = state.services.get(&change.file).and_then(|entry| entry.enabled);
+    state.services.insert(change.file.clone(), ServiceState {
+        hash: calculate_hash(&change.new_content, change.hash_algo),
+        hash_algo: change.hash_algo,
+        unit: (change.file != change.unit).then(|| change.unit.clone()),
+        template: (!change.template.is_empty()).then(|| change.template.clone()),
+        template_hash: Some(change.template_hash.clone()),
+        applied_at: Some(unix_timestamp()),
+        enabled: change.enabled.or(previous_enabled),
+        content: options.store_content.then(|| change.new_content.clone()),
+    });
+}
+
+fn audit_change(change: &ServiceChange, options: &SyncOptions) {
+    if let Some(path) = options.audit_log {
+        AuditEntry {
+            timestamp: unix_timestamp(),
+            unit: &change.file,
+            old_hash: change.old_content.as_deref().map(|content| calculate_hash(content, change.hash_algo)),
+            new_hash: Some(calculate_hash(&change.new_content, change.hash_algo)),
+            overrode_drift: change.state_modified,
+            user: invoking_user(),
+        }
+        .append(path, options.files);
+    }
+}
+
+/// One line of `--audit-log`, for every service that was applied or removed
+#[derive(Debug, Serialize)]
+pub struct AuditEntry<'a> {
+    pub timestamp: u64,
+    pub unit: &'a str,
+    /// Hash of the file that was replaced or removed, absent for new files
+    pub old_hash: Option<String>,
+    /// Hash of the file as written, absent for removals
+    pub new_hash: Option<String>,
+    /// Whether manual edits were overwritten
+    pub overrode_drift: bool,
+    pub user: String,
+}
+
+impl AuditEntry<'_> {
+    // the change already went through at this point, so a log that can't be written only warns
+    fn append(&self, path: &Path, files: &dyn FileSystem) {
+        let result = serde_json::to_string(self).map_err(io::Error::from).and_then(|line| files.append(path, &format!("{}\n", line)));
+        if let Err(err) = result {
+            log::warn!("Failed to append {} to the audit log {}: {}", self.unit, path.display(), err);
+        }
+    }
+}
+
+// who ran sdsync, behind sudo that is the user who invoked sudo rather than root
+fn invoking_user() -> String {
+    if let Some(user) = std::env::var("SUDO_USER").ok().filter(|user| !user.is_empty()) {
+        return user;
+    }
+    let uid = nix::unistd::getuid();
+    match nix::unistd::User::from_uid(uid) {
+        Ok(Some(user)) => user.name,
+        _ => uid.to_string(),
+    }
+}
+
+// settings for applying changes that are the same for every service
+#[derive(Clone, Copy)]
+pub struct SyncOptions<'a> {
+    pub unit_dir: &'a Path,
+    pub backup_dir: &'a Path,
+    pub files: &'a dyn FileSystem,
+    pub runner: &'a dyn CommandRunner,
+    pub user: bool,
+    pub store_content: bool,
+    /// Restart services that aren't running, off with `--no-start-inactive`
+    pub start_inactive: bool,
+    /// How long a restart may take before systemctl is killed
+    pub restart_timeout: Option<std::time::Duration>,
+    /// Pause between restarting one service and the next
+    pub restart_delay: Option<std::time::Duration>,
+    /// Wait for restarted services without a health check to become active
+    pub wait_active: bool,
+    /// Carry on with the other services when one fails and report all failures at the end
+    pub keep_going: bool,
+    /// Treat a failing `post_hook` as an error instead of only warning about it
+    pub strict_hooks: bool,
+    /// Create the unit dir when it's missing instead of failing, off with `--no-create-dir`
+    pub create_dir: bool,
+    /// File every applied and removed service is appended to as a JSON line
+    pub audit_log: Option<&'a Path>,
+}
+
+// runs one of the service's hook commands with the unit it is for in `SDSYNC_UNIT`
+fn run_hook(change: &ServiceChange, hook: &'static str, command: Option<&str>, runner: &dyn CommandRunner) -> Result<(), ManagerError> {
+    let Some(command) = command else {
+        return Ok(());
+    };
+    log::info!("Running {} of {}", hook, change.file);
+    let output = runner.run(Path::new("sh"), &["-c", command], &[("SDSYNC_UNIT", &change.unit)], None)?;
+    if output.success() {
+        return Ok(());
+    }
+    
+    Err(ManagerError::HookFailed {
+        unit: change.file.clone(),
+        hook,
+        code: output.code,
+        output: output.stderr + &output.stdout,
+    })
+}
+
+/// What `write_service` replaced for one of a unit's files, to put it back if the restart fails
+pub struct WrittenUnit<'c> {
+    pub change: &'c ServiceChange,
+    pub previous_content: Option<String>,
+    pub backup_path: Option<PathBuf>,
+}
+
+/// Splits changes into the ones of each unit, in the order their units first come up.
+/// A unit's drop-ins, environment file and other files are synced together with it, so
+/// the unit is restarted and its hooks run once however many of them changed
+pub fn group_by_unit(changes: &[ServiceChange]) -> Vec<Vec<&ServiceChange>> {
+    let mut groups: Vec<Vec<&ServiceChange>> = Vec::new();
+    for change in changes {
+        match groups.iter_mut().find(|group| group[0].unit == change.unit) {
+            Some(group) => group.push(change),
+            None => groups.push(vec![change]),
+        }
+    }
+    groups
+}
+
+// runs a hook of each of the changes, a command several files of the unit share only once
+fn run_unit_hooks<'c>(
+    changes: impl IntoIterator<Item = &'c ServiceChange>,
+    hook: &'static str,
+    command: impl Fn(&'c ServiceChange) -> Option<&'c str>,
+    runner: &dyn CommandRunner,
+) -> Result<(), ManagerError> {
+    let mut ran: Vec<&str> = Vec::new();
+    for change in changes {
+        if let Some(command) = command(change).filter(|command| !ran.contains(command)) {
+            run_hook(change, hook, Some(command), runner)?;
+            ran.push(command);
+        }
+    }
+    Ok(())
+}
+
+// the service is already running its new unit file at this point, so a
+// failing post hook is only an error with `strict_hooks`
+fn run_post_hook(written: &[WrittenUnit], options: &SyncOptions) -> Result<(), ManagerError> {
+    match run_unit_hooks(written.iter().map(|unit| unit.change), "post_hook", |change| change.post_hook.as_deref(), options.runner) {
+        Err(err) if !options.strict_hooks => {
+            log::warn!("{}", err);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+// a failed pre hook leaves its unit alone and only skips it, the other services still sync
+fn is_pre_hook_failure(err: &ManagerError) -> bool {
+    matches!(err, ManagerError::HookFailed { hook: "pre_hook", .. })
+}
+
+// names the service in an error collected with `keep_going`, restart failures already do
+fn service_error(file: &str, err: ManagerError) -> ManagerError {
+    match err {
+        ManagerError::RestartFailed { .. } => err,
+        err => ManagerError::ServiceFailed {
+            unit: file.to_string(),
+            source: Box::new(err),
+        },
+    }
+}
+
+// first phase of syncing a unit: runs its pre hook and writes every one of its
+// files that changed, nothing when none did. When a file fails to write the
+// ones before it are put back. The daemon still has to be reloaded before
+// `restart_service`.
+pub fn write_service<'c>(changes: &[&'c ServiceChange], options: &SyncOptions) -> Result<Vec<WrittenUnit<'c>>, ManagerError> {
+    let changed: Vec<&ServiceChange> = changes.iter().copied().filter(|change| change.content_changed()).collect();
+    if changed.is_empty() {
+        return Ok(Vec::new());
+    }
+    run_unit_hooks(changed.iter().copied(), "pre_hook", |change| change.pre_hook.as_deref(), options.runner)?;
+    
+    let mut written: Vec<WrittenUnit> = Vec::with_capacity(changed.len());
+    for change in changed {
+        match write_unit(change, options) {
+            Ok((previous_content, backup_path)) => written.push(WrittenUnit { change, previous_content, backup_path }),
+            Err(err) => {
+                restore_written(&written, options)?;
+                return Err(err);
+            }
+        }
+    }
+    Ok(written)
+}
+
+// puts back every file of the unit that was written
+fn restore_written(written: &[WrittenUnit], options: &SyncOptions) -> Result<(), ManagerError> {
+    for unit in written {
+        restore_unit(unit.change, options, unit.previous_content.as_deref())?;
+    }
+    Ok(())
+}
+
+// a service failing its health check is running the new unit file, so after
+// restoring the old one it has to be restarted again, or stopped if it's new
+fn recover_unit(written: &[WrittenUnit], options: &SyncOptions) -> Result<(), ManagerError> {
+    let Some(first) = written.first() else {
+        return Ok(());
+    };
+    let created = written.iter().any(|unit| unit.change.file == unit.change.unit && unit.previous_content.is_none());
+    if created {
+        run_systemctl(options.runner, options.user, &first.change.unit, &["stop", &first.change.unit])
+    } else {
+        restart_unit(first.change, options)?;
+        Ok(())
+    }
+}
+
+// second phase: restarts a unit that had files written, putting all of them back
+// when that fails, and records its changes in the state once it is up
+pub fn restart_service(
+    changes: &[&ServiceChange],
+    written: &[WrittenUnit],
+    options: &SyncOptions,
+    state: &mut StateFile,
+) -> Result<(), ManagerError> {
+    if let Some(first) = written.first() {
+        if let Err(err) = restart_checked(first.change, options) {
+            restore_written(written, options)?;
+            run_systemctl(options.runner, options.user, &first.change.unit, &["daemon-reload"])?;
+            if matches!(err, ManagerError::HealthCheckFailed { .. }) {
+                recover_unit(written, options)?;
+            }
+            return Err(ManagerError::RestartFailed {
+                unit: first.change.unit.clone(),
+                source: Box::new(err),
+            });
+        }
+    }
+    
+    for change in changes {
+        enable_unit(change, options)?;
+        record_service(change, options, state);
+    }
+    
+    run_post_hook(written, options)
+}
+
+// reloads the daemon once for every file written
+fn reload_written(written: &[Vec<WrittenUnit>], options: &SyncOptions) -> Result<(), ManagerError> {
+    let files: Vec<&str> = written.iter().flatten().map(|unit| unit.change.file.as_str()).collect();
+    if files.is_empty() {
+        return Ok(());
+    }
+    run_systemctl(options.runner, options.user, &files.join(", "), &["daemon-reload"])
+}
+
+// puts back the unit files of services that were written but won't be
+// restarted because the run stopped early
+fn revert_written(written: &[Vec<WrittenUnit>], options: &SyncOptions) -> Result<(), ManagerError> {
+    for unit in written {
+        restore_written(unit, options)?;
+    }
+    reload_written(written, options)
+}
+
+// writes, reloads and restarts a single unit, returning where the files it replaced were backed up to
+pub fn sync_service(
+    changes: &[&ServiceChange],
+    options: &SyncOptions,
+    state: &mut StateFile,
+) -> Result<Vec<PathBuf>, ManagerError> {
+    let written = write_service(changes, options)?;
+    // need to reload the daemon so it picks up the updated service
+    reload_written(std::slice::from_ref(&written), options)?;
+    restart_service(changes, &written, options, state)?;
+    Ok(written.into_iter().filter_map(|unit| unit.backup_path).collect())
+}
+
+// write_service with the progress messages every way of syncing shows
+fn write_reporting<'c>(
+    changes: &[&'c ServiceChange],
+    options: &SyncOptions,
+    progress: &(dyn Fn(&str) + Sync),
+) -> Result<Vec<WrittenUnit<'c>>, ManagerError> {
+    for change in changes.iter().filter(|change| change.content_changed()) {
+        progress(&format!("Updating service unit file: {}", change.file));
+    }
+    let written = write_service(changes, options)?;
+    for backup_path in written.iter().filter_map(|unit| unit.backup_path.as_ref()) {
+        progress(&format!("Previous unit file saved to {}", backup_path.display()));
+    }
+    Ok(written)
+}
+
+// waits `restart_delay` before every restart but the first, so replicas don't all go down at once
+fn stagger_restart(options: &SyncOptions, restarted: &mut bool) {
+    if let Some(delay) = options.restart_delay.filter(|_| *restarted) {
+        log::info!("Waiting {}s before the next restart", delay.as_secs());
+        std::thread::sleep(delay);
+    }
+    *restarted = true;
+}
+
+// all or nothing: writes and reloads like `sync_sequential`, but when any service
+// fails every written unit file is restored, the services already restarted are
+// restarted again on their previous unit file and the state is left as it was
+pub fn sync_transaction(
+    changes: &[ServiceChange],
+    options: &SyncOptions,
+    state: &mut StateFile,
+    progress: &(dyn Fn(&str) + Sync),
+) -> Result<(), ManagerError> {
+    let units = group_by_unit(changes);
+    let mut written: Vec<Vec<WrittenUnit>> = Vec::with_capacity(units.len());
+    for unit in &units {
+        match write_reporting(unit, options, progress) {
+            Ok(files) => written.push(files),
+            Err(err) => {
+                revert_written(&written, options)?;
+                return Err(err);
+            }
+        }
+    }
+    reload_written(&written, options)?;
+    
+    // only recorded once every service is up, the audit log too
+    let mut staged = state.clone();
+    let staging = SyncOptions { audit_log: None, ..*options };
+    let mut restarted = false;
+    for (index, unit) in units.iter().enumerate() {
+        if !written[index].is_empty() {
+            stagger_restart(options, &mut restarted);
+            progress(&format!("Restarting service: {}", unit[0].unit));
+        }
+        if let Err(err) = restart_service(unit, &written[index], &staging, &mut staged) {
+            progress(&format!("Failed to update {}, rolling back every service: {}", unit[0].unit, err));
+            if matches!(err, ManagerError::RestartFailed { .. }) {
+                // restart_service already put this one back
+                written[index].clear();
+            }
+            revert_written(&written, options)?;
+            for files in written.iter().take(index + 1) {
+                recover_unit(files, options)?;
+            }
+            return Err(err);
+        }
+    }
+    
+    for change in changes {
+        audit_change(change, options);
+    }
+    *state = staged;
+    Ok(())
+}
+
+// writes every changed unit file, reloads the daemon once and then restarts
+// the services one after another in order. Without `keep_going` the first
+// failure stops the run and puts back the files of the services after it,
+// a failing pre hook only skips its own service.
+pub fn sync_sequential(
+    changes: &[ServiceChange],
+    options: &SyncOptions,
+    state: &mut StateFile,
+    progress: &(dyn Fn(&str) + Sync),
+) -> Result<(), ManagerError> {
+    let units = group_by_unit(changes);
+    let mut errors: Vec<ManagerError> = Vec::new();
+    let mut failed: Vec<usize> = Vec::new();
+    let mut written: Vec<Vec<WrittenUnit>> = Vec::with_capacity(units.len());
+    for (index, unit) in units.iter().enumerate() {
+        match write_reporting(unit, options, progress) {
+            Ok(files) => written.push(files),
+            Err(err) if options.keep_going || is_pre_hook_failure(&err) => {
+                progress(&format!("Failed to update {}: {}", unit[0].unit, err));
+                errors.push(service_error(&unit[0].unit, err));
+                failed.push(index);
+                written.push(Vec::new());
+            }
+            Err(err) => {
+                revert_written(&written, options)?;
+                return Err(err);
+            }
+        }
+    }
+    reload_written(&written, options)?;
+    
+    let mut restarted = false;
+    for (index, unit) in units.iter().enumerate() {
+        if failed.contains(&index) {
+            continue;
+        }
+        if !written[index].is_empty() {
+            stagger_restart(options, &mut restarted);
+            progress(&format!("Restarting service: {}", unit[0].unit));
+        }
+        match restart_service(unit, &written[index], options, state) {
+            Ok(()) => {}
+            Err(err) if options.keep_going => {
+                progress(&format!("Failed to update {}: {}", unit[0].unit, err));
+                errors.push(service_error(&unit[0].unit, err));
+            }
+            Err(err) => {
+                revert_written(&written[index + 1..], options)?;
+                return Err(err);
+            }
+        }
+    }
+    
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ManagerError::ServicesFailed(errors))
+    }
+}
+
+// writes every unit first, reloads the daemon once and then restarts up to
+// `parallel` services at a time. A failed restart is rolled back on its own
+// and doesn't stop the others, only the services that came up are recorded.
+pub fn sync_parallel(
+    changes: &[ServiceChange],
+    options: &SyncOptions,
+    parallel: usize,
+    state: &mut StateFile,
+    progress: &(dyn Fn(&str) + Sync),
+) -> Result<(), ManagerError> {
+    let SyncOptions { runner, user, keep_going, .. } = *options;
+    let units = group_by_unit(changes);
+    let mut errors: Vec<ManagerError> = Vec::new();
+    let mut failed: Vec<&str> = Vec::new();
+    let mut written: Vec<Vec<WrittenUnit>> = Vec::with_capacity(units.len());
+    for unit in &units {
+        match write_reporting(unit, options, progress) {
+            Ok(files) => written.push(files),
+            Err(err) if keep_going || is_pre_hook_failure(&err) => {
+                progress(&format!("Failed to update {}: {}", unit[0].unit, err));
+                failed.push(&unit[0].unit);
+                errors.push(service_error(&unit[0].unit, err));
+                written.push(Vec::new());
+            }
+            Err(err) => {
+                // nothing was restarted yet, so put back what was written like sync_sequential does
+                revert_written(&written, options)?;
+                return Err(err);
+            }
+        }
+    }
+    reload_written(&written, options)?;
+    
+    // restart in waves so a service only restarts once everything it depends
+    // on did, the changes are sorted by dependency so those come first
+    let restarting: Vec<usize> = (0..units.len()).filter(|&index| !written[index].is_empty()).collect();
+    let mut waves: Vec<usize> = Vec::with_capacity(restarting.len());
+    for (position, &index) in restarting.iter().enumerate() {
+        let wave = restarting[..position]
+            .iter()
+            .zip(&waves)
+            .filter(|(&dependency, _)| units[index][0].depends_on.contains(&units[dependency][0].unit))
+            .map(|(_, wave)| wave + 1)
+            .max()
+            .unwrap_or(0);
+        waves.push(wave);
+    }
+    
+    let mut results = Vec::new();
+    for wave in 0..=waves.iter().copied().max().unwrap_or(0) {
+        let batch: Vec<usize> = (0..restarting.len()).filter(|&position| waves[position] == wave).map(|position| restarting[position]).collect();
+        let next = std::sync::atomic::AtomicUsize::new(0);
+        results.extend(std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..parallel.clamp(1, batch.len().max(1)))
+                .map(|_| scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let position = next.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        let Some(&index) = batch.get(position) else {
+                            break;
+                        };
+                        let files = &written[index];
+                        progress(&format!("Restarting service: {}", units[index][0].unit));
+                        let restarted = restart_checked(files[0].change, options);
+                        let hook = match restarted {
+                            Ok(()) => run_post_hook(files, options),
+                            Err(_) => Ok(()),
+                        };
+                        results.push((index, restarted, hook));
+                    }
+                    results
+                }))
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("restart worker panicked"))
+                .collect::<Vec<_>>()
+        }));
+    }
+    results.sort_by_key(|(index, _, _)| *index);
+    
+    let mut reverted: Vec<&str> = Vec::new();
+    let mut unhealthy: Vec<usize> = Vec::new();
+    for (index, result, hook) in results {
+        // the restart went through, so the failed hook doesn't undo the change
+        if let Err(err) = hook {
+            errors.push(err);
+        }
+        if let Err(err) = result {
+            restore_written(&written[index], options)?;
+            reverted.extend(written[index].iter().map(|unit| unit.change.file.as_str()));
+            failed.push(&units[index][0].unit);
+            if matches!(err, ManagerError::HealthCheckFailed { .. }) {
+                unhealthy.push(index);
+            }
+            errors.push(ManagerError::RestartFailed {
+                unit: units[index][0].unit.clone(),
+                source: Box::new(err),
+            });
+        }
+    }
+    if !reverted.is_empty() {
+        run_systemctl(runner, user, &reverted.join(", "), &["daemon-reload"])?;
+    }
+    for index in unhealthy {
+        recover_unit(&written[index], options)?;
+    }
+    
+    for change in changes.iter().filter(|change| !failed.contains(&change.unit.as_str())) {
+        match enable_unit(change, options) {
+            Ok(()) => record_service(change, options, state),
+            Err(err) => errors.push(err),
+        }
+    }
+    
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ManagerError::ServicesFailed(errors))
+    }
+}
+
+pub fn remove_service(file: &str, options: &SyncOptions, state: &mut StateFile) -> Result<Option<PathBuf>, ManagerError> {
+    let SyncOptions { unit_dir, backup_dir, files, runner, user, .. } = *options;
+    let service_path = unit_dir.join(file);
+    let mut backup_path = None;
+    
+    // the file may already be gone, in which case systemd doesn't know the unit either
+    let owner = state.services.get(file).and_then(|entry| entry.unit.clone());
+    if let Some(unit) = owner.as_deref().or(dropin_unit(file)) {
+        if files.exists(&service_path) {
+            backup_path = Some(backup_unit(files, &service_path, file, backup_dir)?);
+            files.remove_file(&service_path)?;
+            // the directory is ours to clean up only once no other drop-ins are left in it
+            if file.starts_with(&format!("{}.d/", unit)) {
+                if let Some(dropin_dir) = service_path.parent() {
+                    let _ = files.remove_dir(dropin_dir);
+                }
+            }
+        }
+        run_systemctl(runner, user, unit, &["daemon-reload"])?;
+        // a stopped unit stays stopped, a running one drops the override
+        run_systemctl(runner, user, unit, &["try-restart", unit])?;
+    } else {
+        if files.exists(&service_path) {
+            run_systemctl(runner, user, file, &["stop", file])?;
+            run_systemctl(runner, user, file, &["disable", file])?;
+            backup_path = Some(backup_unit(files, &service_path, file, backup_dir)?);
+            files.remove_file(&service_path)?;
+        }
+        run_systemctl(runner, user, file, &["daemon-reload"])?;
+    }
+        
+    if let (Some(path), Some(entry)) = (options.audit_log, state.services.get(file)) {
+        AuditEntry {
+            timestamp: unix_timestamp(),
+            unit: file,
+            old_hash: Some(entry.hash.clone()),
+            new_hash: None,
+            overrode_drift: false,
+            user: invoking_user(),
+        }
+        .append(path, options.files);
+    }
+    state.services.remove(file);
+    
+    Ok(backup_path)
+}
+
+// masking also stops the unit, a masked service that keeps running isn't what anyone asks for
+pub fn mask_unit(unit: &str, mask: bool, options: &SyncOptions, state: &mut StateFile) -> Result<(), ManagerError> {
+    if mask {
+        run_systemctl(options.runner, options.user, unit, &["mask", "--now", unit])?;
+        if !state.masked.iter().any(|masked| masked == unit) {
+            state.masked.push(unit.to_string());
+            state.masked.sort();
+        }
+    } else {
+        run_systemctl(options.runner, options.user, unit, &["unmask", unit])?;
+        state.masked.retain(|masked| masked != unit);
+    }
+    Ok(())
+}
+
+/// Everything a run would change, in the order it is applied
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// Units to unmask, before any of their files are written
+    pub unmasks: Vec<String>,
+    /// Services whose unit file or enablement differs from the configuration
+    pub changes: Vec<ServiceChange>,
+    /// Files sdsync wrote before that are no longer configured, or belong to a masked service
+    pub removals: Vec<String>,
+    /// Units to mask, once their files are removed
+    pub masks: Vec<String>,
+    /// Files edited outside of sdsync that already have the content the configuration renders, nothing is written for them
+    pub drift: Vec<String>,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.removals.is_empty() && self.masks.is_empty() && self.unmasks.is_empty()
+    }
+    
+    // the first change that would override manual edits
+    pub fn drifted(&self) -> Option<&ServiceChange> {
+        self.changes.iter().find(|change| change.state_modified)
+    }
+}
+
+/// Plans and applies a configuration against a unit directory
+pub struct Syncer<'a> {
+    pub config: &'a Config,
+    pub preview: PreviewOptions<'a>,
+    pub sync: SyncOptions<'a>,
+    /// Units or drop-ins to plan, everything configured when empty
+    pub only: &'a [String],
+    /// Units or drop-ins to leave alone, even when they would be pruned
+    pub exclude: &'a [String],
+    /// Remove what is in the state but no longer configured, never done for runs limited with `only`
+    pub prune: bool,
+    /// Restart up to this many services at a time after a single daemon-reload
+    pub parallel: Option<usize>,
+    /// Roll back every service and leave the state alone when any of them fails
+    pub transaction: bool,
+    /// Apply the first changed service on its own, then ask this whether to go on with the rest
+    pub canary: Option<&'a dyn Fn(&ServiceChange) -> bool>,
+    /// Journal of an interrupted apply to pick up again
+    pub resume: Option<&'a Journal>,
+    /// Rewrite and restart every selected service, also the ones that are up to date
+    pub force_all: bool,
+}
+
+impl<'a> Syncer<'a> {
+    /// The configured services this run covers
+    pub fn services(&self) -> Vec<&'a ServiceConfig> {
+        let selected = |units: &[String], service: &ServiceConfig| units.contains(&service.unit) || units.contains(&service.file());
+        self.config.services
+            .iter()
+            .filter(|service| self.only.is_empty() || selected(self.only, service))
+            .filter(|service| !selected(self.exclude, service))
+            .collect()
+    }
+    
+    /// Renders every selected service and compares it with the unit directory and the state
+    pub fn plan(&self, state: &StateFile) -> Result<Plan, ManagerError> {
+        let mut plan = Plan::default();
+        let PreviewOptions { runner, user, .. } = self.preview;
+        for service in self.services() {
+            if service.is_masked() {
+                // `systemctl mask` refuses while there is a file in the way
+                if state.services.contains_key(&service.file()) {
+                    plan.removals.push(service.file());
+                }
+                if !plan.masks.contains(&service.unit) && !is_masked(runner, user, &service.unit)? {
+                    if state.masked.contains(&service.unit) {
+                        log::warn!("{} was unmasked outside of sdsync", service.unit);
+                    }
+                    plan.masks.push(service.unit.clone());
+                }
+                continue;
+            }
+            let unmask = state.masked.contains(&service.unit) || (service.masked == Some(false) && is_masked(runner, user, &service.unit)?);
+            if unmask && !plan.unmasks.contains(&service.unit) {
+                plan.unmasks.push(service.unit.clone());
+            }
+            
+            let mut change = preview_changes(service, &self.config.variables, &self.preview, state)?;
+            // the files split off a service leave its hooks to the service itself
+            if service.path.is_none() {
+                change.pre_hook = change.pre_hook.or_else(|| self.config.pre_hook.clone());
+                change.post_hook = change.post_hook.or_else(|| self.config.post_hook.clone());
+            }
+            // what the interrupted run wrote isn't a manual edit, but it may never have been restarted
+            if let (Some(journal), Some(content)) = (self.resume, &change.old_content) {
+                if change.state_modified && journal.wrote(&change.file, content) {
+                    change.state_modified = false;
+                    change.rewrite = true;
+                }
+            }
+            if self.force_all {
+                change.rewrite = true;
+            }
+            if change.content_changed() || change.enable.is_some() {
+                plan.changes.push(change);
+            } else if change.state_modified {
+                plan.drift.push(change.file.clone());
+            }
+        }
+        
+        // a unit's files are synced as one, so keep them together from where its first one is
+        let mut changes: Vec<ServiceChange> = Vec::with_capacity(plan.changes.len());
+        for change in plan.changes.drain(..) {
+            match changes.iter().rposition(|other| other.unit == change.unit) {
+                Some(index) => changes.insert(index + 1, change),
+                None => changes.push(change),
+            }
+        }
+        plan.changes = changes;
+        
+        // units we applied before that aren't in the config anymore, a run
+        // limited to some units leaves everything it wasn't asked about alone
+        if self.prune && self.only.is_empty() {
+            let configured: Vec<String> = self.config.services.iter().map(|service| service.file()).collect();
+            let mut removals: Vec<String> = state.services
+                .keys()
+                .filter(|unit| !configured.contains(unit) && !self.exclude.contains(unit))
+                .cloned()
+                .collect();
+            removals.sort();
+            plan.removals.extend(removals);
+            
+            let units = self.config.units();
+            plan.unmasks.extend(state.masked.iter().filter(|unit| !units.contains(unit) && !self.exclude.contains(unit)).cloned());
+        }
+        
+        Ok(plan)
+    }
+    
+    /// Applies a plan, recording every service that was synced in the state even when others failed
+    pub fn apply(&self, plan: &Plan, state: &mut StateFile, progress: &(dyn Fn(&str) + Sync)) -> Result<(), ManagerError> {
+        // minimal containers may not have /etc/systemd/system at all
+        if !self.sync.files.is_dir(self.sync.unit_dir) {
+            if !self.sync.create_dir {
+                return Err(ManagerError::UnitDirNotFound(self.sync.unit_dir.to_path_buf()));
+            }
+            log::info!("Creating unit directory {}", self.sync.unit_dir.display());
+            create_dir(self.sync.files, self.sync.unit_dir)?;
+        }
+        
+        let keep_going = self.sync.keep_going;
+        let mut errors: Vec<ManagerError> = Vec::new();
+        for unit in &plan.unmasks {
+            progress(&format!("Unmasking service: {}", unit));
+            match mask_unit(unit, false, &self.sync, state) {
+                Ok(()) => {}
+                Err(err) if keep_going => {
+                    progress(&format!("Failed to unmask {}: {}", unit, err));
+                    errors.push(service_error(unit, err));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        
+        let mut changes = &plan.changes[..];
+        if let Some(proceed) = self.canary {
+            if let Some(index) = changes.iter().position(|change| change.content_changed()) {
+                // the other files of the canary's unit go with it
+                let end = index + changes[index..].iter().take_while(|change| change.unit == changes[index].unit).count();
+                let (canary, rest) = changes.split_at(end);
+                // the canary has to come up even without a health check of its own
+                let options = SyncOptions { wait_active: true, ..self.sync };
+                self.sync_changes(canary, &options, state, progress, &mut errors)?;
+                if !errors.is_empty() {
+                    return Err(ManagerError::ServicesFailed(errors));
+                }
+                progress(&format!("Canary {} is up", canary[index].unit));
+                if !proceed(&canary[index]) {
+                    return Err(ManagerError::CanaryStopped(canary[index].file.clone()));
+                }
+                changes = rest;
+            }
+        }
+        self.sync_changes(changes, &self.sync, state, progress, &mut errors)?;
+        
+        for unit in &plan.removals {
+            progress(&format!("Removing service: {}", unit));
+            match remove_service(unit, &self.sync, state) {
+                Ok(Some(backup_path)) => progress(&format!("Previous unit file saved to {}", backup_path.display())),
+                Ok(None) => {}
+                Err(err) if keep_going => {
+                    progress(&format!("Failed to remove {}: {}", unit, err));
+                    errors.push(service_error(unit, err));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        for unit in &plan.masks {
+            progress(&format!("Masking service: {}", unit));
+            match mask_unit(unit, true, &self.sync, state) {
+                Ok(()) => {}
+                Err(err) if keep_going => {
+                    progress(&format!("Failed to mask {}: {}", unit, err));
+                    errors.push(service_error(unit, err));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ManagerError::ServicesFailed(errors))
+        }
+    }
+    
+    // syncs the changes the way this run is configured to, collecting failures with `keep_going`
+    fn sync_changes(
+        &self,
+        changes: &[ServiceChange],
+        options: &SyncOptions,
+        state: &mut StateFile,
+        progress: &(dyn Fn(&str) + Sync),
+        errors: &mut Vec<ManagerError>,
+    ) -> Result<(), ManagerError> {
+        let result = match self.parallel {
+            _ if self.transaction => sync_transaction(changes, options, state, progress),
+            Some(parallel) => sync_parallel(changes, options, parallel, state, progress),
+            None => sync_sequential(changes, options, state, progress),
+        };
+        match result {
+            Err(ManagerError::ServicesFailed(failed)) if options.keep_going => errors.extend(failed),
+            result => result?,
+        }
+        Ok(())
+    }
+}
+
+// a directory input stands for the config fragments inside it, in name order
+pub fn config_files(inputs: &[String], files: &dyn FileSystem) -> Result<Vec<String>, ManagerError> {
+    let mut configs = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        if !files.is_dir(path) {
+            configs.push(input.clone());
+            continue;
+        }
+        
+        let mut fragments: Vec<PathBuf> = Vec::new();
+        for fragment in files.read_dir(path)? {
+            let is_config = matches!(fragment.extension().and_then(|ext| ext.to_str()), Some("yaml" | "yml" | "toml"));
+            if is_config && !files.is_dir(&fragment) {
+                fragments.push(fragment);
+            }
+        }
+        fragments.sort();
+        configs.extend(fragments.iter().map(|fragment| fragment.display().to_string()));
+    }
+    Ok(configs)
+}
+
+/// Unit types systemd loads from a unit directory
+pub const UNIT_SUFFIXES: &[&str] = &[
+    ".service", ".socket", ".device", ".mount", ".automount", ".swap",
+    ".target", ".path", ".timer", ".slice", ".scope",
+];
+
+// unit names are joined onto the unit directory, so they must stay a plain file name in it
+pub fn check_unit_name(unit: &str) -> Result<(), ManagerError> {
+    let reason = if unit.contains('/') || unit.contains('\\') {
+        Some("must not contain a path separator")
+    } else if !UNIT_SUFFIXES.iter().any(|suffix| unit.len() > suffix.len() && unit.ends_with(suffix)) {
+        Some("must end in a unit type suffix such as .service or .timer")
+    } else {
+        None
+    };
+    match reason {
+        Some(reason) => Err(ManagerError::InvalidUnitName {
+            unit: unit.to_string(),
+            reason,
+        }),
+        None => Ok(()),
+    }
+}
+
+// merges the configuration files in order, later files override earlier
+// top-level variables but a unit may only be defined once
+pub fn load_config(inputs: &[String], files: &dyn FileSystem) -> Result<Config, ManagerError> {
+    let mut config = Config::default();
+    let mut origins: HashMap<String, String> = HashMap::new();
+    
+    for input in &config_files(inputs, files)? {
+        log::info!("Reading configuration {}", input);
+        // `-` is stdin, which is always YAML
+        let content = if input == "-" {
+            io::read_to_string(io::stdin())?
+        } else {
+            files.read_to_string(Path::new(input))?
+        };
+        let file: Config = if input.ends_with(".toml") {
+            toml::from_str(&content)?
+        } else {
+            serde_yaml::from_str(&content)?
+        };
+        
+        let mut services = Vec::with_capacity(file.services.len());
+        for service in file.services {
+            for instance in service.instantiate()? {
+                services.extend(instance.split_files());
+            }
+        }
+        
+        for service in &services {
+            check_unit_name(&service.unit)?;
+            match (service.template.is_empty(), service.template_inline.is_some()) {
+                (false, true) => return Err(ManagerError::InvalidTemplate {
+                    unit: service.file(),
+                    reason: "sets both template and template_inline",
+                }),
+                (true, false) if !service.is_masked() => return Err(ManagerError::InvalidTemplate {
+                    unit: service.file(),
+                    reason: "needs a template or template_inline",
+                }),
+                _ => {}
+            }
+            if service.path.as_ref().is_some_and(|path| path.is_empty() || path.ends_with('/')) {
+                return Err(ManagerError::InvalidUnitName {
+                    unit: service.unit.clone(),
+                    reason: "environment_file and files need the path of a file",
+                });
+            }
+            // the path is also where its backups go in the backup dir, `..` would leave it
+            if service.path.as_ref().is_some_and(|path| Path::new(path).components().any(|component| component == Component::ParentDir)) {
+                return Err(ManagerError::InvalidUnitName {
+                    unit: service.unit.clone(),
+                    reason: "environment_file and files paths must not contain `..`",
+                });
+            }
+            if service.dropin.as_ref().is_some_and(|name| name.is_empty() || name.contains('/')) {
+                return Err(ManagerError::InvalidUnitName {
+                    unit: service.file(),
+                    reason: "drop-in names must be a plain file name",
+                });
+            }
+            if let Some(first) = origins.insert(service.file(), input.clone()) {
+                if first != *input {
+                    return Err(ManagerError::DuplicateUnit {
+                        unit: service.file(),
+                        first,
+                        second: input.clone(),
+                    });
+                }
+            }
+        }
+        config.variables.extend(file.variables);
+        config.pre_hook = file.pre_hook.or(config.pre_hook);
+        config.post_hook = file.post_hook.or(config.post_hook);
+        config.services.extend(services);
+    }
+    
+    // depending on a template unit means depending on every instance of it
+    let instances: Vec<(String, String)> = config
+        .services
+        .iter()
+        .filter_map(|service| {
+            let (prefix, rest) = service.unit.split_once('@')?;
+            let (_, suffix) = rest.rsplit_once('.')?;
+            Some((format!("{}@.{}", prefix, suffix), service.unit.clone()))
+        })
+        .collect();
+    for service in &mut config.services {
+        let mut depends_on = Vec::with_capacity(service.depends_on.len());
+        for dependency in service.depends_on.drain(..) {
+            let matching: Vec<String> = instances
+                .iter()
+                .filter(|(template, _)| *template == dependency)
+                .map(|(_, unit)| unit.clone())
+                .collect();
+            if matching.is_empty() {
+                depends_on.push(dependency);
+            } else {
+                depends_on.extend(matching);
+            }
+        }
+        service.depends_on = depends_on;
+    }
+    
+    // the same unit twice in one file would share a state entry, so whichever came last would win
+    let mut seen: Vec<String> = Vec::new();
+    let mut duplicates: Vec<String> = Vec::new();
+    for file in config.services.iter().map(ServiceConfig::file) {
+        if seen.contains(&file) {
+            if !duplicates.contains(&file) {
+                duplicates.push(file);
+            }
+        } else {
+            seen.push(file);
+        }
+    }
+    if !duplicates.is_empty() {
+        return Err(ManagerError::DuplicateUnits(duplicates));
+    }
+    
+    config.sort_by_dependencies()?;
+    Ok(config)
+}
+
+// errors out on any of the requested units that isn't one of the valid ones
+pub fn check_units(requested: &[String], valid: &[String]) -> Result<(), ManagerError> {
+    let unknown: Vec<String> = requested
+        .iter()
+        .filter(|unit| !valid.contains(unit))
+        .cloned()
+        .collect();
+    if !unknown.is_empty() {
+        return Err(ManagerError::UnknownUnits {
+            unknown,
+            valid: valid.to_vec(),
+        });
+    }
+    Ok(())
+}
+
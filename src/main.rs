@@ -1,285 +1,1927 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::{self, IsTerminal};
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use console::Style;
-use minijinja::Environment;
-use serde::{Deserialize, Serialize};
-use sha2::{Sha256, Digest};
+use serde::Serialize;
 use similar::{ChangeTag, TextDiff};
-use dialoguer::Confirm;
-use std::fmt;
-use clap::Parser;
+use dialoguer::{Confirm, Select};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use sdsync::{
+    calculate_hash, check_unit_name, check_units, check_variables, default_unit_dir,
+    find_backup, list_backups, load_config, load_secrets, masked_values, preview_changes,
+    render_service, restart_with_mode, run_systemctl, set_systemctl_path, template_syntax_line,
+    undo_run, unix_timestamp, unused_templates, verify_unit, write_atomic, Config,
+    DryRunFileSystem, FilePermissions, FileSystem, HashAlgo, Journal, LastRun, ManagerError,
+    OsFileSystem, Plan, PreviewOptions, RenderOptions, RestartMode, ServiceChange,
+    ServiceConfig, ServiceState, StateFile, StateLock, SyncOptions, Syncer, SystemRunner,
+    Templates, VariableOverride,
+};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-    /// Directory containing service templates
-    #[arg(long, default_value = "templates")]
-    templates: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // without a subcommand sdsync applies, as it did before there were any
+    #[command(flatten)]
+    apply: ApplyArgs,
+
+    /// Log diagnostics to stderr, -v for info and -vv for debug
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Print diffs and warnings without colors, which is also the default when NO_COLOR is set or stdout isn't a terminal
+    #[arg(long, global = true)]
+    no_color: bool,
+
+    /// systemctl binary to run instead of the one on PATH, e.g. a wrapper
+    #[arg(long, global = true, value_name = "PATH")]
+    systemctl_path: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ApplyArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+
+    #[command(flatten)]
+    target: TargetArgs,
 
     /// Force apply changes even if state is outdated
     #[arg(long)]
     force: bool,
 
-    /// File containing the configuration for the template.
+    /// Rewrite and restart every service, also the ones that are already up to date
+    #[arg(long)]
+    force_all: bool,
+
+    /// Show the planned changes without writing files or touching systemd
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Only tell through the exit code whether anything would change, a dry run without the diffs
+    #[arg(long)]
+    check: bool,
+
+    /// Apply the changes without asking for confirmation
     #[arg(short, long)]
-    input: String,
+    yes: bool,
 
-    /// File that will store the state file
+    /// Append a JSON line for every service that was applied or removed to this file
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+
+    /// Show every change on its own and ask whether to apply it, skip it or stop
+    #[arg(long, conflicts_with = "yes")]
+    confirm_each: bool,
+
+    /// Hash algorithm used for new state entries, existing entries keep the one they were written with
+    #[arg(long, value_enum, default_value_t = HashAlgo::Sha256)]
+    hash_algo: HashAlgo,
+
+    /// Keep a copy of the rendered unit files in the state file to show manual edits on drift
+    #[arg(long)]
+    store_content: bool,
+
+    /// Only analyze and apply this unit, can be repeated
+    #[arg(long, value_name = "UNIT")]
+    only: Vec<String>,
+
+    /// Leave this unit untouched, can be repeated and wins over --only
+    #[arg(long, value_name = "UNIT")]
+    exclude: Vec<String>,
+
+    /// Only restart services that are running, stopped ones just get the new unit file
+    #[arg(long)]
+    no_start_inactive: bool,
+
+    /// Seconds a restart may take before it is killed and counted as failed
+    #[arg(long, value_name = "SECS")]
+    restart_timeout: Option<u64>,
+
+    /// Seconds to wait between restarting one service and the next
+    #[arg(long, value_name = "SECS", conflicts_with = "parallel")]
+    restart_delay: Option<u64>,
+
+    /// Wait for restarted services to become active, rolling back the ones that don't within --restart-timeout (30s by default)
+    #[arg(long)]
+    wait_active: bool,
+
+    /// Keep applying the other services when one fails, then report every failure and exit non-zero
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Fail when a post_hook fails instead of only warning, the service itself stays updated
+    #[arg(long)]
+    strict_hooks: bool,
+
+    /// Fail when the unit directory doesn't exist instead of creating it
+    #[arg(long)]
+    no_create_dir: bool,
+
+    /// URL to POST a JSON list of the changed units to after a successful apply
+    #[arg(long, value_name = "URL")]
+    notify_url: Option<String>,
+
+    /// Also notify --notify-url when applying fails
+    #[arg(long, requires = "notify_url")]
+    notify_on_failure: bool,
+
+    /// Restart up to this many services at once, after writing all unit files and reloading the daemon a single time
+    #[arg(long, value_name = "N")]
+    parallel: Option<usize>,
+
+    /// Restore every unit file and leave the state unchanged when any service fails to restart
+    #[arg(long, conflicts_with_all = ["parallel", "keep_going"])]
+    transaction: bool,
+
+    /// Apply the first changed service alone, wait for it to be healthy and confirm before applying the rest
+    #[arg(long, conflicts_with = "transaction")]
+    canary: bool,
+
+    /// Check the rendered units with `systemd-analyze verify` and abort if any is rejected
+    #[arg(long)]
+    verify: bool,
+
+    /// Keep services that are in the state file but no longer in the configuration
+    #[arg(long)]
+    no_prune: bool,
+
+    #[command(flatten)]
+    diff: DiffDisplayArgs,
+
+    /// Only print errors and the final result, leaving out progress and diffs
     #[arg(short, long)]
-    state: String,
+    quiet: bool,
+
+    /// Format of the plan printed before applying
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
 }
 
-#[derive(Debug)]
-enum ManagerError {
-    Io(std::io::Error),
-    Template(minijinja::Error),
-    Yaml(serde_yaml::Error),
-    TemplateNotFound(PathBuf),
-    StateOutOfSync(String),
+impl ApplyArgs {
+    fn dry_run(&self) -> bool {
+        self.dry_run || self.check
+    }
 }
 
-impl fmt::Display for ManagerError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            ManagerError::Io(err) => write!(f, "IO error: {}", err),
-            ManagerError::Template(err) => write!(f, "Template error: {}", err),
-            ManagerError::Yaml(err) => write!(f, "YAML error: {}", err),
-            ManagerError::TemplateNotFound(path) => write!(f, "Template not found: {}", path.display()),
-            ManagerError::StateOutOfSync(service) => write!(f, "Service {} has been modified outside of this tool", service),
+// how diffs are shown, shared by apply and diff
+#[derive(clap::Args, Debug)]
+struct DiffDisplayArgs {
+    /// Lines of unchanged context shown around each change in diffs
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    diff_context: usize,
+
+    /// Show the whole unit file in diffs instead of only the changed hunks, never truncated
+    #[arg(long)]
+    full_diff: bool,
+
+    /// Stop each diff after this many changed lines and say how many were left out
+    #[arg(long, value_name = "N")]
+    max_diff_lines: Option<usize>,
+
+    /// Whether changed lines are shown as a whole or with the changed words highlighted
+    #[arg(long, value_enum, default_value_t = DiffMode::Line)]
+    diff_mode: DiffMode,
+
+    /// Mask the value of this variable in diffs wherever it shows up, as resolved for each service. Can be repeated
+    #[arg(long, value_name = "KEY")]
+    redact: Vec<String>,
+}
+
+impl DiffDisplayArgs {
+    fn context(&self) -> Option<usize> {
+        if self.full_diff {
+            None
+        } else {
+            Some(self.diff_context)
         }
     }
+    
+    fn max_lines(&self) -> Option<usize> {
+        self.max_diff_lines.filter(|_| !self.full_diff)
+    }
 }
 
-impl std::error::Error for ManagerError {}
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Render the services and sync the changed ones, the default without a subcommand
+    Apply(ApplyArgs),
+    /// Print the diffs of the services that would change and nothing else
+    Diff(DiffArgs),
+    /// Report which services are in sync, drifted or would change, without writing or restarting anything
+    Status(StatusArgs),
+    /// List the configured services with their template and whether their unit file is as last applied
+    List(ListArgs),
+    /// Restore a unit file from one of its backups
+    Rollback(RollbackArgs),
+    /// Revert every unit file the last apply changed or removed and restart the services involved
+    Undo(UndoArgs),
+    /// Render every service to check the configuration without touching the system or the state
+    Validate(ValidateArgs),
+    /// Render every service into a directory without touching systemd or the state
+    Export(ExportArgs),
+    /// Adopt existing unit files by writing them as templates and seeding the configuration and state
+    Import(ImportArgs),
+    /// Create a templates directory and a configuration to start from
+    Init(InitArgs),
+    /// Print a shell completion script to stdout
+    #[command(hide = true)]
+    Completions {
+        shell: clap_complete::Shell,
+    },
+}
 
-impl From<std::io::Error> for ManagerError {
-    fn from(err: std::io::Error) -> ManagerError {
-        ManagerError::Io(err)
-    }
+#[derive(clap::Args, Debug)]
+struct ValidateArgs {
+    #[command(flatten)]
+    source: SourceArgs,
 }
 
-impl From<minijinja::Error> for ManagerError {
-    fn from(err: minijinja::Error) -> ManagerError {
-        ManagerError::Template(err)
-    }
+#[derive(clap::Args, Debug)]
+struct ExportArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+
+    /// Directory to write the rendered unit files to, created if it doesn't exist
+    #[arg(long)]
+    out: PathBuf,
+
+    /// Overwrite unit files that already exist in the output directory
+    #[arg(long)]
+    force: bool,
 }
 
-impl From<serde_yaml::Error> for ManagerError {
-    fn from(err: serde_yaml::Error) -> ManagerError {
-        ManagerError::Yaml(err)
-    }
+#[derive(clap::Args, Debug)]
+struct StatusArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+
+    #[command(flatten)]
+    target: TargetArgs,
+
+    /// Format of the status report
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ServiceConfig {
-    template: String,
-    unit: String,
-    variables: HashMap<String, String>,
+#[derive(clap::Args, Debug)]
+struct ListArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+
+    #[command(flatten)]
+    target: TargetArgs,
+
+    /// Format of the list
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Config {
-    services: Vec<ServiceConfig>,
+#[derive(clap::Args, Debug)]
+struct DiffArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+
+    #[command(flatten)]
+    target: TargetArgs,
+
+    #[command(flatten)]
+    diff: DiffDisplayArgs,
+
+    /// Format of the diffs
+    #[arg(long, value_enum, default_value_t = DiffFormat::Text)]
+    output: DiffFormat,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct StateFile {
-    services: HashMap<String, String>,
+#[derive(clap::Args, Debug)]
+struct InitArgs {
+    /// Directory to create the templates and configuration in
+    #[arg(default_value = ".")]
+    dir: PathBuf,
+
+    /// Overwrite the example template and configuration if they already exist
+    #[arg(long)]
+    force: bool,
 }
 
-#[derive(Debug)]
-struct ServiceChange {
-    unit: String,
-    old_content: Option<String>,
-    new_content: String,
-    state_modified: bool,
+#[derive(clap::Args, Debug)]
+struct ImportArgs {
+    /// Directory the unit files are copied into as templates
+    #[arg(long, default_value = "templates")]
+    templates: PathBuf,
+
+    /// Configuration file to write, as TOML when it ends in `.toml` and YAML otherwise
+    #[arg(long)]
+    out: PathBuf,
+
+    /// Overwrite the configuration and templates if they already exist
+    #[arg(long)]
+    force: bool,
+
+    /// Hash algorithm used for the seeded state entries
+    #[arg(long, value_enum, default_value_t = HashAlgo::Sha256)]
+    hash_algo: HashAlgo,
+
+    #[command(flatten)]
+    target: TargetArgs,
 }
 
-impl StateFile {
-    fn load_or_create(path: &Path) -> Result<Self, ManagerError> {
-        if path.exists() {
-            let content = fs::read_to_string(path)?;
-            Ok(serde_yaml::from_str(&content).unwrap_or_else(|_| StateFile {
-                services: HashMap::new(),
-            }))
+// the configuration and what is needed to render it
+#[derive(clap::Args, Debug)]
+struct SourceArgs {
+    /// Directory containing service templates
+    #[arg(long, default_value = "templates")]
+    templates: PathBuf,
+
+    /// File containing the configuration for the template, a directory of `*.yaml`/`*.yml`/`*.toml` fragments, or `-` for YAML on stdin. TOML is used for `.toml` files, YAML otherwise. Can be repeated to merge several
+    #[arg(short, long, required = true)]
+    input: Vec<String>,
+
+    /// Directory templates read files from with `file("path")`, the templates directory when unset
+    #[arg(long, value_name = "DIR")]
+    files_dir: Option<PathBuf>,
+
+    /// YAML or JSON file with secret variables for every service, their values are masked in diffs
+    #[arg(long)]
+    secrets: Option<PathBuf>,
+
+    /// Fail on templates using variables that aren't defined instead of rendering them empty, checked for all services before rendering any
+    #[arg(long)]
+    strict_vars: bool,
+
+    /// Use variable values as written instead of expanding `${NAME}` from the environment
+    #[arg(long)]
+    no_env_expand: bool,
+
+    /// Fail instead of warning when the templates directory has templates no service uses
+    #[arg(long)]
+    strict_templates: bool,
+
+    /// Fail instead of warning when a rendered unit still contains `{{`, `{%` or `{#`
+    #[arg(long)]
+    strict_syntax: bool,
+
+    /// Fail instead of warning when a rendered unit file lacks the section for its type, like [Service] or [Timer]
+    #[arg(long)]
+    strict_sections: bool,
+
+    /// Set a variable for every service, or only for UNIT, over the configuration and secrets. Can be repeated
+    #[arg(long = "set", value_name = "[UNIT:]KEY=VALUE")]
+    set: Vec<VariableOverride>,
+}
+
+impl SourceArgs {
+    // the merged configuration, with the units `--set` is limited to checked against it
+    fn load_config(&self) -> Result<Config, ManagerError> {
+        let config = load_config(&self.input, &OsFileSystem)?;
+        let scoped: Vec<String> = self.set.iter().filter_map(|entry| entry.unit.clone()).collect();
+        check_units(&scoped, &config.units())?;
+        Ok(config)
+    }
+    
+    fn load_secrets(&self) -> Result<BTreeMap<String, serde_yaml::Value>, ManagerError> {
+        match &self.secrets {
+            Some(path) => load_secrets(path, &OsFileSystem),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+    
+    // templates nothing renders are usually left over from a rename
+    fn check_templates(&self, config: &Config) -> Result<(), ManagerError> {
+        let unused = unused_templates(config, &self.templates, &OsFileSystem)?;
+        if unused.is_empty() {
+            Ok(())
+        } else if self.strict_templates {
+            Err(ManagerError::UnusedTemplates(unused))
         } else {
-            Ok(StateFile {
-                services: HashMap::new(),
-            })
+            log::warn!("Templates not used by any service: {}", unused.join(", "));
+            Ok(())
         }
     }
-
-    fn save(&self, path: &Path) -> Result<(), ManagerError> {
-        let content = serde_yaml::to_string(self)?;
-        Ok(fs::write(path, content)?)
+    
+    fn templates(&self) -> Templates {
+        let templates = Templates::new(&self.templates, self.strict_vars);
+        match &self.files_dir {
+            Some(dir) => templates.with_files_dir(dir),
+            None => templates,
+        }
     }
-
-    fn validate_service(&self, unit: &str, content: &str) -> bool {
-        match self.services.get(unit) {
-            Some(stored_hash) => calculate_hash(content) == *stored_hash,
-            None => true,
+    
+    fn render_options<'a>(&'a self, secrets: &'a BTreeMap<String, serde_yaml::Value>, templates: &'a Templates) -> RenderOptions<'a> {
+        RenderOptions {
+            templates,
+            expand_env: !self.no_env_expand,
+            secrets,
+            overrides: &self.set,
+            strict_syntax: self.strict_syntax,
+            strict_sections: self.strict_sections,
         }
     }
 }
 
-fn calculate_hash(content: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(content.as_bytes());
-    format!("{:x}", hasher.finalize())
+#[derive(clap::Args, Debug)]
+struct RollbackArgs {
+    /// Unit to restore
+    #[arg(long)]
+    unit: String,
+
+    /// Timestamp of the backup to restore, picked interactively when left out
+    #[arg(long)]
+    timestamp: Option<String>,
+
+    /// Configuration the unit's mode, owner, group and restart mode are taken from. Without it the restored file keeps the mode and owner of the one it replaces and the service is restarted
+    #[arg(short, long)]
+    input: Vec<String>,
+
+    /// Only restart the service if it is running, a stopped one just gets the restored unit file
+    #[arg(long)]
+    no_start_inactive: bool,
+
+    #[command(flatten)]
+    target: TargetArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct UndoArgs {
+    /// Undo even when unit files were changed since the last apply, discarding those changes
+    #[arg(long)]
+    force: bool,
+
+    /// Skip the confirmation prompt
+    #[arg(short, long)]
+    yes: bool,
+
+    #[command(flatten)]
+    target: TargetArgs,
 }
 
-fn render_template(template_dir: &Path, template_name: &str, variables: &HashMap<String, String>) -> Result<String, ManagerError> {
-    let template_path = template_dir.join(template_name);
-    if !template_path.exists() {
-        return Err(ManagerError::TemplateNotFound(template_path));
+// where units, state and backups live, shared by every command touching the system
+#[derive(clap::Args, Debug)]
+struct TargetArgs {
+    /// Directory the systemd unit files are written to [default: /etc/systemd/system, or the user unit directory with --user]
+    #[arg(long)]
+    unit_dir: Option<PathBuf>,
+
+    /// Manage user services through `systemctl --user` instead of system services
+    #[arg(long)]
+    user: bool,
+
+    /// Directory previous unit files are copied to before being replaced [default: backups/ next to the state file, or <state-dir>/backups/<name>]
+    #[arg(long)]
+    backup_dir: Option<PathBuf>,
+
+    /// File that will store the state file
+    #[arg(short, long, required_unless_present = "state_dir")]
+    state: Option<String>,
+
+    /// Directory holding the state files of several hosts, each stored as `<name>.yaml`
+    #[arg(long, conflicts_with = "state")]
+    state_dir: Option<PathBuf>,
+
+    /// Name of the state file in --state-dir [default: the hostname]
+    #[arg(long, requires = "state_dir", conflicts_with = "state")]
+    name: Option<String>,
+
+    /// Seconds to wait for another run holding the state lock before giving up
+    #[arg(long, default_value_t = 0)]
+    lock_timeout: u64,
+
+    /// Start over with an empty state when the state file is corrupt
+    #[arg(long)]
+    reset_state: bool,
+}
+
+impl TargetArgs {
+    fn unit_dir(&self) -> Result<PathBuf, ManagerError> {
+        match &self.unit_dir {
+            Some(dir) => Ok(dir.clone()),
+            None => default_unit_dir(self.user),
+        }
+    }
+    
+    fn state_path(&self) -> Result<PathBuf, ManagerError> {
+        match (&self.state, &self.state_dir) {
+            (Some(state), _) => Ok(PathBuf::from(state)),
+            (None, Some(dir)) => StateFile::path_in(dir, self.name.as_deref()),
+            (None, None) => unreachable!("--state or --state-dir is required"),
+        }
     }
     
-    let template_content = fs::read_to_string(&template_path)?;
-    let mut env = Environment::new();
-    env.add_template("service", &template_content)?;
+    fn lock_state(&self) -> Result<StateLock, ManagerError> {
+        // the first run on a host starts the directory, the lock lives next to the state
+        if let Some(dir) = &self.state_dir {
+            OsFileSystem.create_dir_all(dir)?;
+        }
+        StateLock::acquire(&self.state_path()?, std::time::Duration::from_secs(self.lock_timeout), &OsFileSystem)
+    }
     
-    let template = env.get_template("service")?;
-    Ok(template.render(variables)?)
+    fn load_state(&self) -> Result<StateFile, ManagerError> {
+        match StateFile::load_or_create(&self.state_path()?, &OsFileSystem) {
+            Err(ManagerError::CorruptState { path, reason }) if self.reset_state => {
+                log::warn!("Resetting corrupt state file {} ({})", path.display(), reason);
+                Ok(StateFile::default())
+            }
+            result => result,
+        }
+    }
+    
+    fn backup_dir(&self) -> Result<PathBuf, ManagerError> {
+        let state_path = self.state_path()?;
+        Ok(match (&self.backup_dir, &self.state_dir) {
+            (Some(dir), _) => dir.clone(),
+            // hosts sharing a state dir keep their backups apart
+            (None, Some(dir)) => dir.join("backups").join(state_path.file_stem().unwrap_or_default()),
+            (None, None) => state_path.parent().unwrap_or(Path::new("")).join("backups"),
+        })
+    }
 }
 
-fn print_diff(old_content: Option<&str>, new_content: &str, unit: &str, state_modified: bool) {
-    let old_content = old_content.unwrap_or("");
-    let diff = TextDiff::from_lines(old_content, new_content);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human readable diffs and actions
+    Text,
+    /// Machine readable plan on stdout
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DiffFormat {
+    /// Colored diffs per service
+    Text,
+    /// A unified diff of the unit dir for `patch -p1`
+    Patch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DiffMode {
+    /// Changed lines are removed and added as a whole
+    Line,
+    /// Changed lines also highlight the words that changed in them
+    Word,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum PlanAction {
+    New,
+    Updated,
+    Removed,
+    Masked,
+    Unmasked,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ServiceStatus {
+    InSync,
+    /// The unit file was edited outside of sdsync
+    Drifted,
+    /// Applied before, but the unit file is gone
+    Missing,
+    /// Never applied and not on disk yet
+    New,
+    /// On disk with other content, but never applied by sdsync
+    Unmanaged,
+    /// Would change with the current templates and variables
+    Changed,
+    /// Applied before and unchanged, but no longer configured
+    Unconfigured,
+    /// Masked through the configuration
+    Masked,
+    /// The unit file is what was last applied, whether the templates would still render it isn't checked
+    Unmodified,
+}
+
+impl ServiceStatus {
+    fn of(change: &ServiceChange, state: &StateFile) -> Self {
+        if change.old_content.is_none() {
+            if state.services.contains_key(&change.file) {
+                ServiceStatus::Missing
+            } else {
+                ServiceStatus::New
+            }
+        } else if change.state_modified {
+            ServiceStatus::Drifted
+        } else if change.content_changed() && !state.services.contains_key(&change.file) {
+            ServiceStatus::Unmanaged
+        } else if change.content_changed() || change.enable.is_some() {
+            ServiceStatus::Changed
+        } else {
+            ServiceStatus::InSync
+        }
+    }
+    
+    fn description(self) -> &'static str {
+        match self {
+            ServiceStatus::InSync => "in sync",
+            ServiceStatus::Drifted => "drifted, modified outside of sdsync",
+            ServiceStatus::Missing => "missing, the unit file was removed",
+            ServiceStatus::New => "not applied yet",
+            ServiceStatus::Unmanaged => "exists with other content, but wasn't written by sdsync",
+            ServiceStatus::Changed => "would change",
+            ServiceStatus::Unconfigured => "no longer configured, removed by the next apply",
+            ServiceStatus::Masked => "masked",
+            ServiceStatus::Unmodified => "unmodified since the last apply",
+        }
+    }
     
+    // the short name for tables, as in the json
+    fn label(self) -> &'static str {
+        match self {
+            ServiceStatus::InSync => "in-sync",
+            ServiceStatus::Drifted => "drifted",
+            ServiceStatus::Missing => "missing",
+            ServiceStatus::New => "new",
+            ServiceStatus::Unmanaged => "unmanaged",
+            ServiceStatus::Changed => "changed",
+            ServiceStatus::Unconfigured => "unconfigured",
+            ServiceStatus::Masked => "masked",
+            ServiceStatus::Unmodified => "unmodified",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct StatusEntry {
+    unit: String,
+    status: ServiceStatus,
+    template_updated: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ListEntry {
+    unit: String,
+    /// Absent for `template_inline` and masked services
+    template: Option<String>,
+    status: ServiceStatus,
+}
+
+#[derive(Debug, Serialize)]
+struct PlanEntry {
+    unit: String,
+    action: PlanAction,
+    state_modified: bool,
+    template_changed: bool,
+    template_updated: bool,
+    old_hash: Option<String>,
+    new_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    enable: Option<bool>,
+}
+
+// other files of a service are listed with their unit, a drop-in's path already names it
+fn change_title(change: &ServiceChange) -> String {
+    if change.file.starts_with(&change.unit) {
+        change.file.clone()
+    } else {
+        format!("{} of {}", change.file, change.unit)
+    }
+}
+
+fn print_diff(old_content: Option<&str>, new_content: &str, unit: &str, state_modified: bool, options: &DiffOptions) {
     println!("\nChanges for {}:", unit);
     if state_modified {
-        println!("⚠️  WARNING: This service has been modified outside of this tool!");
+        println!("{}", Style::new().yellow().bold().apply_to("⚠️  WARNING: This service has been modified outside of this tool!"));
     }
     println!("----------------------------");
-    
-    for change in diff.iter_all_changes() {
-        let (sign, style) = match change.tag() {
+    match old_content {
+        Some(old_content) => print_lines(old_content, new_content, options),
+        None => print_lines("", new_content, &DiffOptions { context: None, ..*options }),
+    }
+    println!("----------------------------\n");
+}
+
+// prints a line diff with every occurrence of a secret value masked, limited
+// to the changed hunks when a context size is set
+fn print_lines(old_content: &str, new_content: &str, options: &DiffOptions) {
+    let diff = TextDiff::from_lines(old_content, new_content);
+    let changed = diff.iter_all_changes().filter(|change| change.tag() != ChangeTag::Equal).count();
+    let shown = Cell::new(0);
+    let truncated = || options.max_lines.is_some_and(|max| shown.get() >= max);
+    // segments are the words of a line, the emphasized ones are what actually changed
+    let print_change = |tag: ChangeTag, segments: &[(bool, Cow<str>)]| {
+        if truncated() {
+            return;
+        }
+        if tag != ChangeTag::Equal {
+            shown.set(shown.get() + 1);
+        }
+        let (sign, style) = match tag {
             ChangeTag::Delete => ("-", Style::new().red()),
             ChangeTag::Insert => ("+", Style::new().green()),
             ChangeTag::Equal => (" ", Style::new()),
         };
         
-        print!("{}", style.apply_to(format!("{}{}", sign, change)));
+        let mut line: String = segments.iter().map(|(_, text)| text.as_ref()).collect();
+        // the last line of a file may not end in a newline
+        let newline = if line.ends_with('\n') { "" } else { "\n" };
+        // emphasis could split a secret up, so a line with one in it is masked as a whole
+        if segments.len() == 1 || options.secrets.iter().any(|secret| line.contains(secret.as_str())) {
+            for secret in options.secrets {
+                line = line.replace(secret.as_str(), "***");
+            }
+            print!("{}{}", style.apply_to(format!("{}{}", sign, line)), newline);
+            return;
+        }
+        print!("{}", style.apply_to(sign));
+        for (emphasized, text) in segments {
+            let (text, end) = text.strip_suffix('\n').map_or((text.as_ref(), ""), |text| (text, "\n"));
+            let style = if *emphasized { style.clone().reverse() } else { style.clone() };
+            print!("{}{}", style.apply_to(text), end);
+        }
+        print!("{}", newline);
+    };
+    let print_op = |op: &similar::DiffOp| match options.mode {
+        DiffMode::Line => diff.iter_changes(op).for_each(|change| print_change(change.tag(), &[(false, Cow::Borrowed(change.value()))])),
+        DiffMode::Word => diff.iter_inline_changes(op).for_each(|change| {
+            print_change(change.tag(), &change.iter_strings_lossy().collect::<Vec<_>>())
+        }),
+    };
+    
+    match options.context {
+        Some(context) => {
+            for group in diff.grouped_ops(context) {
+                if truncated() {
+                    break;
+                }
+                println!("{}", Style::new().cyan().apply_to(similar::udiff::UnifiedHunkHeader::new(&group)));
+                group.iter().for_each(print_op);
+            }
+        }
+        None => diff.ops().iter().for_each(print_op),
     }
-    println!("----------------------------\n");
+    if changed > shown.get() {
+        println!("{}", Style::new().dim().apply_to(format!("… ({} more changed lines)", changed - shown.get())));
+    }
+}
+
+// prints a change as a unified diff with paths relative to the unit dir,
+// new and removed unit files are diffed against /dev/null
+fn print_patch(old_content: Option<&str>, new_content: Option<&str>, unit: &str, options: &DiffOptions) {
+    let mask = |content: &str| {
+        options.secrets.iter().fold(content.to_string(), |content, secret| content.replace(secret.as_str(), "***"))
+    };
+    let old_content = old_content.map(mask);
+    let new_content = new_content.map(mask);
+    let diff = TextDiff::from_lines(old_content.as_deref().unwrap_or(""), new_content.as_deref().unwrap_or(""));
+    
+    // an environment file outside the unit dir keeps its absolute path below a/ and b/
+    let unit = unit.trim_start_matches('/');
+    let old_path = if old_content.is_some() { format!("a/{}", unit) } else { "/dev/null".to_string() };
+    let new_path = if new_content.is_some() { format!("b/{}", unit) } else { "/dev/null".to_string() };
+    // the whole file fits in one hunk's context, a bigger radius overflows in similar
+    let context = options.context.unwrap_or(diff.old_slices().len().max(diff.new_slices().len()));
+    print!("{}", diff.unified_diff().context_radius(context).header(&old_path, &new_path));
+}
+
+// tells apart manual edits from template updates for a drifted service
+fn print_drift(change: &ServiceChange, options: &DiffOptions) {
+    if change.template_changed {
+        println!("The template for {} also changed since the last apply, both the manual edits and the template update are shown above.", change.file);
+    } else {
+        println!("The template for {} renders the same as the last apply, applying only reverts the manual edits.", change.file);
+    }
+    
+    if let (Some(last_applied), Some(current)) = (&change.last_applied_content, &change.old_content) {
+        println!("\nManual edits to {} since the last apply:", change.file);
+        println!("----------------------------");
+        print_lines(last_applied, current, options);
+        println!("----------------------------\n");
+    }
+}
+
+/// What a run ended up doing, printed once it's done
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    created: Vec<String>,
+    updated: Vec<String>,
+    removed: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    masked: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unmasked: Vec<String>,
+    /// Services that were already up to date
+    skipped: Vec<String>,
+    /// Services whose manual edits were overridden with --force
+    overridden: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed: Vec<String>,
+}
+
+impl Summary {
+    // the state only records what went through, so it tells what failed
+    fn new(services: &[&ServiceConfig], plan: &Plan, state: &StateFile) -> Self {
+        let mut summary = Summary::default();
+        for change in &plan.changes {
+            let applied = state.services.get(&change.file).is_some_and(|entry| {
+                entry.hash == calculate_hash(&change.new_content, entry.hash_algo)
+                    && change.enable.is_none_or(|enable| entry.enabled == Some(enable))
+            });
+            if !applied {
+                summary.failed.push(change.file.clone());
+                continue;
+            }
+            if change.old_content.is_none() {
+                summary.created.push(change.file.clone());
+            } else {
+                summary.updated.push(change.file.clone());
+            }
+            if change.state_modified {
+                summary.overridden.push(change.file.clone());
+            }
+        }
+        for file in &plan.removals {
+            if state.services.contains_key(file) {
+                summary.failed.push(file.clone());
+            } else {
+                summary.removed.push(file.clone());
+            }
+        }
+        for unit in &plan.masks {
+            if state.masked.contains(unit) {
+                summary.masked.push(unit.clone());
+            } else {
+                summary.failed.push(unit.clone());
+            }
+        }
+        for unit in &plan.unmasks {
+            if state.masked.contains(unit) {
+                summary.failed.push(unit.clone());
+            } else {
+                summary.unmasked.push(unit.clone());
+            }
+        }
+        summary.skipped = services
+            .iter()
+            .filter(|service| !service.is_masked())
+            .map(|service| service.file())
+            .filter(|file| !plan.changes.iter().any(|change| change.file == *file))
+            .collect();
+        summary
+    }
+    
+    fn print(&self) {
+        println!(
+            "\nSummary: {} created, {} updated, {} removed, {} skipped",
+            self.created.len(),
+            self.updated.len(),
+            self.removed.len(),
+            self.skipped.len(),
+        );
+        if !self.masked.is_empty() {
+            println!(" * Masked: {}", self.masked.join(", "));
+        }
+        if !self.unmasked.is_empty() {
+            println!(" * Unmasked: {}", self.unmasked.join(", "));
+        }
+        if !self.overridden.is_empty() {
+            println!(" ! Overrode manual changes to: {}", self.overridden.join(", "));
+        }
+        if !self.failed.is_empty() {
+            println!(" ! Failed: {}", self.failed.join(", "));
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Notification<'a> {
+    status: &'static str,
+    timestamp: u64,
+    units: Vec<NotifiedUnit<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct NotifiedUnit<'a> {
+    unit: &'a str,
+    action: PlanAction,
+    /// Hash of the unit file as written, absent for removals
+    hash: Option<String>,
+}
+
+// posts what was applied to --notify-url, the changes are already
+// made at this point so a failed notification only warns
+// how long a notification may take before it is given up on, an unreachable
+// endpoint mustn't keep the apply from finishing
+const NOTIFY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+fn notify(url: &str, summary: &Summary, plan: &Plan, error: Option<&ManagerError>) {
+    let mut units: Vec<NotifiedUnit> = Vec::new();
+    for change in &plan.changes {
+        let action = if summary.created.contains(&change.file) {
+            PlanAction::New
+        } else if summary.updated.contains(&change.file) {
+            PlanAction::Updated
+        } else {
+            continue;
+        };
+        units.push(NotifiedUnit {
+            unit: &change.file,
+            action,
+            hash: Some(calculate_hash(&change.new_content, change.hash_algo)),
+        });
+    }
+    units.extend(summary.removed.iter().map(|file| NotifiedUnit {
+        unit: file,
+        action: PlanAction::Removed,
+        hash: None,
+    }));
+    units.extend(summary.masked.iter().map(|unit| NotifiedUnit {
+        unit,
+        action: PlanAction::Masked,
+        hash: None,
+    }));
+    units.extend(summary.unmasked.iter().map(|unit| NotifiedUnit {
+        unit,
+        action: PlanAction::Unmasked,
+        hash: None,
+    }));
+    
+    let notification = Notification {
+        status: if error.is_some() { "failure" } else { "success" },
+        timestamp: unix_timestamp(),
+        units,
+        error: error.map(|err| err.to_string()),
+    };
+    let result = serde_json::to_string(&notification)
+        .map_err(|err| err.to_string())
+        .and_then(|body| {
+            let agent: ureq::Agent = ureq::Agent::config_builder()
+                .timeout_global(Some(NOTIFY_TIMEOUT))
+                .build()
+                .into();
+            agent.post(url)
+                .header("Content-Type", "application/json")
+                .send(&body)
+                .map_err(|err| err.to_string())
+        });
+    if let Err(err) = result {
+        log::warn!("Failed to notify {}: {}", url, err);
+    }
+}
+
+fn print_json_plan(plan: &Plan, state: &StateFile) -> Result<(), ManagerError> {
+    let unit_entry = |unit: &String, action: PlanAction| PlanEntry {
+        unit: unit.clone(),
+        action,
+        state_modified: false,
+        template_changed: false,
+        template_updated: false,
+        old_hash: None,
+        new_hash: None,
+        enable: None,
+    };
+    let mut entries: Vec<PlanEntry> = plan.changes
+        .iter()
+        .map(|change| PlanEntry {
+            unit: change.file.clone(),
+            action: if change.old_content.is_some() { PlanAction::Updated } else { PlanAction::New },
+            state_modified: change.state_modified,
+            template_changed: change.template_changed,
+            template_updated: change.template_updated,
+            old_hash: change.old_content.as_deref().map(|content| calculate_hash(content, change.hash_algo)),
+            new_hash: Some(calculate_hash(&change.new_content, change.hash_algo)),
+            enable: change.enable,
+        })
+        .chain(plan.removals.iter().map(|unit| PlanEntry {
+            unit: unit.clone(),
+            action: PlanAction::Removed,
+            state_modified: false,
+            template_changed: false,
+            template_updated: false,
+            old_hash: state.services.get(unit).map(|entry| entry.hash.clone()),
+            new_hash: None,
+            enable: None,
+        }))
+        .chain(plan.masks.iter().map(|unit| unit_entry(unit, PlanAction::Masked)))
+        .chain(plan.unmasks.iter().map(|unit| unit_entry(unit, PlanAction::Unmasked)))
+        .collect();
+    entries.sort_by(|a, b| a.unit.cmp(&b.unit));
+    
+    println!("{}", serde_json::to_string_pretty(&entries)?);
+    Ok(())
 }
 
-fn preview_changes(
-    config: &ServiceConfig,
-    template_dir: &Path,
-    state: &StateFile,
-) -> Result<ServiceChange, ManagerError> {
-    let new_content = render_template(template_dir, &config.template, &config.variables)?;
-    let service_path = Path::new("/etc/systemd/system").join(&config.unit);
+// how diffs are printed
+#[derive(Clone, Copy)]
+struct DiffOptions<'a> {
+    /// Lines of unchanged context around each hunk, the whole file when unset
+    context: Option<usize>,
+    /// Values to mask with `***`
+    secrets: &'a [String],
+    /// Changed lines shown before the rest of a diff is left out, all when unset
+    max_lines: Option<usize>,
+    mode: DiffMode,
+}
+
+fn rollback(args: &RollbackArgs) -> Result<(), ManagerError> {
+    check_unit_name(&args.unit)?;
+    let unit_dir = args.target.unit_dir()?;
+    let backup_dir = args.target.backup_dir()?;
+    let state_path = &args.target.state_path()?;
+    let _lock = args.target.lock_state()?;
+    let mut state = args.target.load_state()?;
+    
+    let timestamps = list_backups(&OsFileSystem, &args.unit, &backup_dir)?;
+    if timestamps.is_empty() {
+        return Err(ManagerError::NoBackups(args.unit.clone()));
+    }
+    
+    let timestamp = match &args.timestamp {
+        Some(timestamp) => timestamp.clone(),
+        None if io::stdin().is_terminal() => {
+            let selected = Select::new()
+                .with_prompt(format!("Select the backup of {} to restore", args.unit))
+                .items(&timestamps)
+                .default(timestamps.len() - 1)
+                .interact()?;
+            timestamps[selected].clone()
+        }
+        None => timestamps[timestamps.len() - 1].clone(),
+    };
+    
+    let backup_path = find_backup(&OsFileSystem, &args.unit, &backup_dir, &timestamp)?;
+    
+    // the service's configuration decides the mode, owner and restart, like an apply would
+    let config = if args.input.is_empty() { None } else { Some(load_config(&args.input, &OsFileSystem)?) };
+    let service = config.as_ref().and_then(|config| config.services.iter().find(|service| service.file() == args.unit));
+    let unit_path = unit_dir.join(&args.unit);
+    let permissions = match service {
+        Some(service) => FilePermissions::resolve(service, args.target.user)?,
+        None if OsFileSystem.exists(&unit_path) => OsFileSystem.permissions(&unit_path)?,
+        None => FilePermissions { mode: 0o644, uid: None, gid: None },
+    };
+    let restart_mode = service.map(|service| service.restart_mode).unwrap_or_default();
+    
+    println!("Restoring {} from {}", args.unit, backup_path.display());
+    let content = OsFileSystem.read_to_string(&backup_path)?;
+    OsFileSystem.write(&unit_path, &content, Some(&permissions))?;
+    
+    run_systemctl(&SystemRunner, args.target.user, &args.unit, &["daemon-reload"])?;
+    restart_with_mode(&SystemRunner, args.target.user, &args.unit, restart_mode, !args.no_start_inactive, None)?;
+    
+    // the restored content didn't come from the current template, so only its hash and
+    // the content itself, when the state keeps it, are known
+    let hash_algo = state.services.get(&args.unit).map(|entry| entry.hash_algo).unwrap_or_default();
+    let hash = calculate_hash(&content, hash_algo);
+    let entry = state.services
+        .entry(args.unit.clone())
+        .or_insert_with(|| ServiceState::new(hash.clone(), hash_algo));
+    entry.hash = hash;
+    entry.template_hash = None;
+    entry.applied_at = Some(unix_timestamp());
+    if entry.content.is_some() {
+        entry.content = Some(content);
+    }
+    state.save(state_path, &OsFileSystem)?;
+    
+    println!("Service {} restored successfully!", args.unit);
     
-    let (old_content, state_modified) = if service_path.exists() {
-        let content = fs::read_to_string(&service_path)?;
-        let valid = state.validate_service(&config.unit, &content);
-        (Some(content), !valid)
+    Ok(())
+}
+
+fn undo(args: &UndoArgs) -> Result<ExitCode, ManagerError> {
+    let unit_dir = args.target.unit_dir()?;
+    let state_path = &args.target.state_path()?;
+    let _lock = args.target.lock_state()?;
+    let mut state = args.target.load_state()?;
+    
+    let run_path = LastRun::path(state_path);
+    let Some(run) = LastRun::load(&run_path, &OsFileSystem)? else {
+        return Err(ManagerError::NothingToUndo(run_path));
+    };
+    
+    // undoing over manual edits would throw them away without a backup
+    let modified = run.modified(&unit_dir, &OsFileSystem);
+    if !modified.is_empty() {
+        if !args.force {
+            return Err(ManagerError::UndoModified(modified));
+        }
+        for file in &modified {
+            log::warn!("Discarding the changes made to {} since the last apply", file);
+        }
+    }
+    
+    println!("The last apply changed:");
+    for entry in &run.changes {
+        match (&entry.previous_content, &entry.hash) {
+            (Some(_), Some(_)) if entry.content_changed() => println!(" * Restore previous unit file: {}", entry.file),
+            (Some(_), Some(_)) => {}
+            (Some(_), None) => println!(" * Restore removed unit file: {}", entry.file),
+            (None, Some(_)) => println!(" * Remove new unit file: {}", entry.file),
+            (None, None) => println!(" * Forget removed service: {}", entry.file),
+        }
+        match entry.enabled.filter(|_| entry.previous_content.is_some()) {
+            Some(true) => println!(" * Disable service again: {}", entry.unit),
+            Some(false) => println!(" * Enable service again: {}", entry.unit),
+            None => {}
+        }
+    }
+    
+    if !args.yes {
+        if !io::stdin().is_terminal() {
+            return Err(ManagerError::NotInteractive);
+        }
+        if !Confirm::new().with_prompt("Do you want to undo these changes?").interact()? {
+            println!("Operation cancelled.");
+            return Ok(ExitCode::from(EXIT_DECLINED));
+        }
+    }
+    
+    undo_run(&run, &unit_dir, &OsFileSystem, &SystemRunner, args.target.user, &mut state, &|message| println!("{}", message))?;
+    state.save(state_path, &OsFileSystem)?;
+    // the undo itself isn't undone, so the same run can't be reverted twice
+    LastRun::remove(&run_path, &OsFileSystem)?;
+    
+    println!("The last apply was undone successfully!");
+    Ok(ExitCode::SUCCESS)
+}
+
+fn validate(args: &ValidateArgs) -> Result<(), ManagerError> {
+    let config = args.source.load_config()?;
+    args.source.check_templates(&config)?;
+    let services: Vec<&ServiceConfig> = config.services.iter().filter(|service| !service.is_masked()).collect();
+    let secrets = args.source.load_secrets()?;
+    let templates = args.source.templates();
+    if args.source.strict_vars {
+        check_variables(&services, &config.variables, &secrets, &args.source.set, &templates)?;
+    }
+    let options = args.source.render_options(&secrets, &templates);
+    
+    println!("Validating {} services...", services.len());
+    let mut errors: Vec<ManagerError> = Vec::new();
+    for service in services {
+        match render_service(service, &config.variables, &options) {
+            Ok(_) => println!(" * {}: ok", service.file()),
+            Err(err) => {
+                println!(" ! {}: {}", service.file(), err);
+                errors.push(err);
+            }
+        }
+    }
+    
+    if errors.is_empty() {
+        println!("Configuration is valid!");
+        Ok(())
     } else {
-        (None, false)
+        Err(ManagerError::ServicesFailed(errors))
+    }
+}
+
+fn status(args: &StatusArgs) -> Result<(), ManagerError> {
+    let config = args.source.load_config()?;
+    let secrets = args.source.load_secrets()?;
+    let templates = args.source.templates();
+    let unit_dir = args.target.unit_dir()?;
+    let state = args.target.load_state()?;
+    let options = PreviewOptions {
+        render: args.source.render_options(&secrets, &templates),
+        unit_dir: &unit_dir,
+        files: &OsFileSystem,
+        runner: &SystemRunner,
+        user: args.target.user,
+        hash_algo: HashAlgo::default(),
     };
     
-    Ok(ServiceChange {
-        unit: config.unit.clone(),
-        old_content,
-        new_content,
-        state_modified,
-    })
+    let mut entries: Vec<StatusEntry> = Vec::new();
+    for service in config.services.iter().filter(|service| !service.is_masked()) {
+        let change = preview_changes(service, &config.variables, &options, &state)?;
+        entries.push(StatusEntry {
+            unit: change.file.clone(),
+            status: ServiceStatus::of(&change, &state),
+            template_updated: change.template_updated,
+        });
+    }
+    // what sdsync wrote before is checked against the state even when nothing renders it anymore
+    let mut leftover: Vec<&String> = state.services.keys().filter(|file| !entries.iter().any(|entry| entry.unit == **file)).collect();
+    leftover.sort();
+    for file in leftover {
+        let path = unit_dir.join(file);
+        let status = if !options.files.exists(&path) {
+            ServiceStatus::Missing
+        } else if !state.validate_service(file, &options.files.read_to_string(&path)?) {
+            ServiceStatus::Drifted
+        } else {
+            ServiceStatus::Unconfigured
+        };
+        entries.push(StatusEntry { unit: file.clone(), status, template_updated: false });
+    }
+    
+    match args.output {
+        OutputFormat::Text => {
+            for entry in &entries {
+                let marker = if entry.status == ServiceStatus::InSync { '*' } else { '!' };
+                let note = if entry.template_updated { " (the template changed since the last apply)" } else { "" };
+                println!(" {} {}: {}{}", marker, entry.unit, entry.status.description(), note);
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&entries)?),
+    }
+    
+    let out_of_sync: Vec<String> = entries
+        .into_iter()
+        .filter(|entry| entry.status != ServiceStatus::InSync)
+        .map(|entry| entry.unit)
+        .collect();
+    if out_of_sync.is_empty() {
+        Ok(())
+    } else {
+        Err(ManagerError::NotInSync(out_of_sync))
+    }
 }
 
-fn sync_service(change: &ServiceChange, state: &mut StateFile) -> Result<(), ManagerError> {
-    let service_path = Path::new("/etc/systemd/system").join(&change.unit);
-    let new_hash = calculate_hash(&change.new_content);
+// only compares the unit files with the state, so an unmodified one may still be out of date,
+// what the templates would render is left to status
+fn list(args: &ListArgs) -> Result<(), ManagerError> {
+    let config = args.source.load_config()?;
+    let unit_dir = args.target.unit_dir()?;
+    let state = args.target.load_state()?;
+    let files: &dyn FileSystem = &OsFileSystem;
     
-    fs::write(&service_path, &change.new_content)?;
+    let mut entries: Vec<ListEntry> = Vec::new();
+    for service in &config.services {
+        let file = service.file();
+        let path = unit_dir.join(&file);
+        let applied = state.services.contains_key(&file);
+        let status = if service.is_masked() {
+            ServiceStatus::Masked
+        } else if !files.exists(&path) && applied {
+            ServiceStatus::Missing
+        } else if !files.exists(&path) {
+            ServiceStatus::New
+        } else if !applied {
+            ServiceStatus::Unmanaged
+        } else if state.validate_service(&file, &files.read_to_string(&path)?) {
+            ServiceStatus::Unmodified
+        } else {
+            ServiceStatus::Drifted
+        };
+        let template = (service.template_inline.is_none() && !service.template.is_empty()).then(|| service.template.clone());
+        entries.push(ListEntry { unit: file, template, status });
+    }
     
-    // need to reload the daemon so it picks up the updated service
-    std::process::Command::new("systemctl")
-        .arg("daemon-reload")
-        .status()?;
-        
-    std::process::Command::new("systemctl")
-        .args(["restart", &change.unit])
-        .status()?;
-        
-    state.services.insert(change.unit.clone(), new_hash);
+    match args.output {
+        OutputFormat::Text => {
+            let templates: Vec<&str> = config.services
+                .iter()
+                .zip(&entries)
+                .map(|(service, entry)| match &entry.template {
+                    Some(template) => template.as_str(),
+                    None if service.template_inline.is_some() => "(inline)",
+                    None => "-",
+                })
+                .collect();
+            let unit_width = entries.iter().map(|entry| entry.unit.chars().count()).chain(["UNIT".len()]).max().unwrap_or_default();
+            let template_width = templates.iter().map(|template| template.chars().count()).chain(["TEMPLATE".len()]).max().unwrap_or_default();
+            println!("{:<unit_width$}  {:<template_width$}  STATUS", "UNIT", "TEMPLATE");
+            for (entry, template) in entries.iter().zip(&templates) {
+                println!("{:<unit_width$}  {:<template_width$}  {}", entry.unit, template, entry.status.label());
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&entries)?),
+    }
+    Ok(())
+}
+
+fn diff(args: &DiffArgs) -> Result<(), ManagerError> {
+    let config = args.source.load_config()?;
+    let secrets = args.source.load_secrets()?;
+    let templates = args.source.templates();
+    let masked = masked_values(&config, &args.diff.redact, &args.source.render_options(&secrets, &templates))?;
+    let unit_dir = args.target.unit_dir()?;
+    let state = args.target.load_state()?;
+    let options = PreviewOptions {
+        render: args.source.render_options(&secrets, &templates),
+        unit_dir: &unit_dir,
+        files: &OsFileSystem,
+        runner: &SystemRunner,
+        user: args.target.user,
+        hash_algo: HashAlgo::default(),
+    };
+    let diff_options = DiffOptions {
+        context: args.diff.context(),
+        secrets: &masked,
+        max_lines: args.diff.max_lines(),
+        mode: args.diff.diff_mode,
+    };
     
+    for service in config.services.iter().filter(|service| !service.is_masked()) {
+        let change = preview_changes(service, &config.variables, &options, &state)?;
+        if !change.content_changed() {
+            continue;
+        }
+        match args.output {
+            DiffFormat::Text => print_diff(change.old_content.as_deref(), &change.new_content, &change_title(&change), change.state_modified, &diff_options),
+            DiffFormat::Patch => print_patch(change.old_content.as_deref(), Some(&change.new_content), &change.file, &diff_options),
+        }
+    }
+    
+    // the files an apply would prune, shown as deleted as a whole
+    let configured: Vec<String> = config.services.iter().filter(|service| !service.is_masked()).map(ServiceConfig::file).collect();
+    let mut removals: Vec<&String> = state.services.keys().filter(|file| !configured.contains(file)).collect();
+    removals.sort();
+    for file in removals {
+        let path = unit_dir.join(file);
+        if !options.files.exists(&path) {
+            continue;
+        }
+        let content = options.files.read_to_string(&path)?;
+        match args.output {
+            DiffFormat::Text => print_diff(Some(&content), "", &format!("{} (removed)", file), false, &diff_options),
+            DiffFormat::Patch => print_patch(Some(&content), None, file, &diff_options),
+        }
+    }
     Ok(())
 }
 
-fn main() -> Result<(), ManagerError> {
-    let args = Args::parse();
+fn export(args: &ExportArgs) -> Result<(), ManagerError> {
+    let config = args.source.load_config()?;
+    args.source.check_templates(&config)?;
+    let services: Vec<&ServiceConfig> = config.services.iter().filter(|service| !service.is_masked()).collect();
+    let secrets = args.source.load_secrets()?;
+    let templates = args.source.templates();
+    if args.source.strict_vars {
+        check_variables(&services, &config.variables, &secrets, &args.source.set, &templates)?;
+    }
+    let options = args.source.render_options(&secrets, &templates);
     
-    let config_content = fs::read_to_string(&args.input)?;
-    let config: Config = serde_yaml::from_str(&config_content)?;
+    // render everything first so a broken template doesn't leave a partial export behind
+    let mut rendered = Vec::new();
+    for service in services {
+        let content = render_service(service, &config.variables, &options)?;
+        // environment files may have absolute paths, which still belong inside the export
+        rendered.push((args.out.join(service.file().trim_start_matches('/')), content));
+    }
+    
+    if !args.force {
+        let existing: Vec<PathBuf> = rendered.iter()
+            .map(|(path, _)| path.clone())
+            .filter(|path| path.exists())
+            .collect();
+        if !existing.is_empty() {
+            return Err(ManagerError::OutputExists(existing));
+        }
+    }
     
-    let state_path = Path::new(&args.state);
-    let mut state = StateFile::load_or_create(state_path)?;
+    for (path, content) in &rendered {
+        fs::create_dir_all(path.parent().unwrap_or(&args.out))?;
+        println!("Writing {}", path.display());
+        write_atomic(path, content, None)?;
+    }
     
-    let mut changes: Vec<ServiceChange> = Vec::new();
+    println!("Exported {} units to {}", rendered.len(), args.out.display());
     
-    println!("Analyzing changes...");
-    for service_config in &config.services {
-        let change = preview_changes(service_config, &args.templates, &state)?;
+    Ok(())
+}
+
+const INIT_TEMPLATE: &str = "\
+[Unit]
+Description={{ description }}
+
+[Service]
+Type=oneshot
+ExecStart=/usr/bin/echo {{ message }}
+";
+
+const INIT_CONFIG: &str = "\
+# Variables shared by every service, a service's own variables win
+variables:
+  message: hello from sdsync
+
+services:
+    # template in the templates directory to render
+  - template: example.service
+    # unit file written to /etc/systemd/system, or the user unit dir with --user
+    unit: sdsync-example.service
+    # values for the template, `${NAME}` is read from the environment
+    variables:
+      description: Example service managed by sdsync
+    # enable the unit on boot, left alone when not set
+    # enabled: true
+    # how a running service picks up changes: restart, reload, reload-or-restart or none
+    # restart_mode: restart
+";
+
+fn init(args: &InitArgs) -> Result<(), ManagerError> {
+    let template_path = args.dir.join("templates").join("example.service");
+    let config_path = args.dir.join("config.yaml");
+    let files = [(&template_path, INIT_TEMPLATE), (&config_path, INIT_CONFIG)];
+    
+    if !args.force {
+        let existing: Vec<PathBuf> = files.iter()
+            .map(|(path, _)| path.to_path_buf())
+            .filter(|path| path.exists())
+            .collect();
+        if !existing.is_empty() {
+            return Err(ManagerError::OutputExists(existing));
+        }
+    }
+    
+    for (path, content) in files {
+        fs::create_dir_all(path.parent().unwrap_or(&args.dir))?;
+        println!("Writing {}", path.display());
+        write_atomic(path, content, None)?;
+    }
+    
+    let input = config_path.display();
+    let templates = args.dir.join("templates");
+    let templates = templates.display();
+    let state = args.dir.join("state.yml");
+    let state = state.display();
+    println!("\nNext steps:");
+    println!(" * Replace {} with your own templates and list them in {}", template_path.display(), input);
+    println!(" * Check that everything renders: sdsync validate --templates {} --input {}", templates, input);
+    println!(" * See what would change: sdsync diff --templates {} --input {} --state {}", templates, input, state);
+    println!(" * Apply it: sudo sdsync --templates {} --input {} --state {}", templates, input, state);
+    
+    Ok(())
+}
+
+// turns a unit file into a template rendering back to exactly the same content
+fn escape_template(content: &str) -> String {
+    if content.contains("{{") || content.contains("{%") || content.contains("{#") {
+        format!("{{% raw %}}{}{{% endraw %}}", content)
+    } else if content.ends_with('\n') {
+        // minijinja drops a single trailing newline when rendering
+        format!("{}\n", content)
+    } else {
+        content.to_string()
+    }
+}
+
+fn import(args: &ImportArgs) -> Result<(), ManagerError> {
+    let unit_dir = args.target.unit_dir()?;
+    if !unit_dir.exists() {
+        return Err(ManagerError::UnitDirNotFound(unit_dir));
+    }
+    
+    // symlinks are aliases or enablement links managed by systemctl, not units of their own,
+    // and anything without a unit suffix isn't loaded by systemd
+    let mut units = Vec::new();
+    for entry in fs::read_dir(&unit_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            if let Some(name) = entry.file_name().to_str() {
+                if check_unit_name(name).is_ok() {
+                    units.push(name.to_string());
+                }
+            }
+        }
+    }
+    units.sort();
+    
+    let templates: Vec<PathBuf> = units.iter().map(|unit| args.templates.join(unit)).collect();
+    if !args.force {
+        let existing: Vec<PathBuf> = std::iter::once(&args.out)
+            .chain(&templates)
+            .filter(|path| path.exists())
+            .cloned()
+            .collect();
+        if !existing.is_empty() {
+            return Err(ManagerError::OutputExists(existing));
+        }
+    }
+    
+    let state_path = &args.target.state_path()?;
+    let _lock = args.target.lock_state()?;
+    let mut state = args.target.load_state()?;
+    
+    fs::create_dir_all(&args.templates)?;
+    let mut config = Config::default();
+    for (unit, template_path) in units.iter().zip(&templates) {
+        println!("Importing {}", unit);
+        let content = fs::read_to_string(unit_dir.join(unit))?;
+        let template = escape_template(&content);
+        write_atomic(template_path, &template, None)?;
         
-        let needs_update = match &change.old_content {
-            Some(old_content) => old_content != &change.new_content,
-            None => true,
-        };
+        let mut entry = ServiceState::new(calculate_hash(&content, args.hash_algo), args.hash_algo);
+        entry.template = Some(unit.clone());
+        entry.template_hash = Some(calculate_hash(&template, args.hash_algo));
+        entry.applied_at = Some(unix_timestamp());
+        state.services.insert(unit.clone(), entry);
         
-        if needs_update {
-            // if state is modified and --force is not used, return error
-            if change.state_modified && !args.force {
-                return Err(ManagerError::StateOutOfSync(change.unit.clone()));
+        config.services.push(ServiceConfig {
+            template: unit.clone(),
+            template_inline: None,
+            unit: unit.clone(),
+            variables: BTreeMap::new(),
+            enabled: None,
+            masked: None,
+            restart_mode: RestartMode::default(),
+            mode: None,
+            owner: None,
+            group: None,
+            dropin: None,
+            health_check: None,
+            depends_on: Vec::new(),
+            pre_hook: None,
+            post_hook: None,
+            instances: Vec::new(),
+            environment_file: None,
+            literal_braces: template_syntax_line(&content).is_some(),
+            files: Vec::new(),
+            path: None,
+        });
+    }
+    
+    let serialized = if args.out.extension().is_some_and(|ext| ext == "toml") {
+        toml::to_string(&config)?
+    } else {
+        serde_yaml::to_string(&config)?
+    };
+    write_atomic(&args.out, &serialized, None)?;
+    state.save(state_path, &OsFileSystem)?;
+    
+    println!("Imported {} units into {}", units.len(), args.out.display());
+    
+    Ok(())
+}
+
+// the part of a plan that was approved one change at a time, `None` when the run was aborted
+fn confirm_each(plan: Plan, diff_options: &DiffOptions) -> Result<Option<Plan>, ManagerError> {
+    let Some(unmasks) = approve(plan.unmasks, |unit| format!("Unmask {}?", unit))? else {
+        return Ok(None);
+    };
+    let Some(changes) = approve(plan.changes, |change| {
+        if change.differs() {
+            print_diff(change.old_content.as_deref(), &change.new_content, &change_title(change), change.state_modified, diff_options);
+            if change.state_modified {
+                print_drift(change, diff_options);
             }
-            changes.push(change);
+        }
+        format!("Apply the changes to {}?", change.file)
+    })?
+    else {
+        return Ok(None);
+    };
+    let Some(removals) = approve(plan.removals, |file| format!("Remove {}?", file))? else {
+        return Ok(None);
+    };
+    let Some(masks) = approve(plan.masks, |unit| format!("Mask {}?", unit))? else {
+        return Ok(None);
+    };
+    Ok(Some(Plan { unmasks, changes, removals, masks, drift: plan.drift }))
+}
+
+// `ask` shows an item and returns the question for it, skipped items are left out
+fn approve<T>(items: Vec<T>, ask: impl Fn(&T) -> String) -> Result<Option<Vec<T>>, ManagerError> {
+    let mut approved = Vec::new();
+    for item in items {
+        let choice = Select::new()
+            .with_prompt(ask(&item))
+            .items(&["Apply", "Skip", "Abort"])
+            .default(0)
+            .interact()?;
+        match choice {
+            0 => approved.push(item),
+            1 => {}
+            _ => return Ok(None),
         }
     }
+    Ok(Some(approved))
+}
+
+// a journal is only left behind by an apply that was killed halfway, picking
+// it up restarts what that run wrote instead of calling it a manual edit
+fn resume_journal(path: &Path, args: &ApplyArgs) -> Result<Option<Journal>, ManagerError> {
+    let Some(journal) = Journal::load(path, &OsFileSystem)? else {
+        return Ok(None);
+    };
+    let files: Vec<&str> = journal.changes.iter().map(|entry| entry.file.as_str()).collect();
+    log::warn!(
+        "An apply {}s ago was interrupted while updating {}",
+        unix_timestamp().saturating_sub(journal.started_at),
+        files.join(", ")
+    );
+    if args.yes || args.dry_run() {
+        return Ok(Some(journal));
+    }
+    if !io::stdin().is_terminal() {
+        return Err(ManagerError::NotInteractive);
+    }
     
-    if changes.is_empty() {
-        println!("No changes needed for any services");
-        return Ok(());
+    if Confirm::new()
+        .with_prompt("Resume it? Otherwise the unit files it wrote count as manual changes")
+        .interact()?
+    {
+        Ok(Some(journal))
+    } else {
+        Journal::remove(path, &OsFileSystem)?;
+        Ok(None)
     }
+}
+
+fn apply(args: &ApplyArgs) -> Result<ExitCode, ManagerError> {
+    let config = args.source.load_config()?;
+    check_units(&args.only, &config.units())?;
+    args.source.check_templates(&config)?;
     
-    println!("\nPlanned changes:");
-    for change in &changes {
-        print_diff(
-            change.old_content.as_deref(),
-            &change.new_content,
-            &change.unit,
-            change.state_modified,
-        );
+    let unit_dir = args.target.unit_dir()?;
+    let backup_dir = args.target.backup_dir()?;
+    
+    let state_path = &args.target.state_path()?;
+    // a dry run never writes state, so it doesn't need the lock (or write access to take it)
+    let _lock = if args.dry_run() { None } else { Some(args.target.lock_state()?) };
+    let mut state = args.target.load_state()?;
+    
+    // excluding a unit that is only left in the state keeps it from being pruned
+    let mut known = config.units();
+    let leftover: Vec<String> = state.services.keys().filter(|unit| !known.contains(unit)).cloned().collect();
+    known.extend(leftover);
+    check_units(&args.exclude, &known)?;
+    
+    let journal_path = Journal::path(state_path);
+    let journal = resume_journal(&journal_path, args)?;
+    
+    let text = args.output == OutputFormat::Text;
+    let secrets = args.source.load_secrets()?;
+    let templates = args.source.templates();
+    let masked = masked_values(&config, &args.diff.redact, &args.source.render_options(&secrets, &templates))?;
+    let diff_options = DiffOptions {
+        context: args.diff.context(),
+        secrets: &masked,
+        max_lines: args.diff.max_lines(),
+        mode: args.diff.diff_mode,
+    };
+    // with --yes a canary that came up is enough to go on
+    let confirm_canary = |change: &ServiceChange| {
+        args.yes
+            || Confirm::new()
+                .with_prompt(format!("{} was applied and is up, apply the remaining changes?", change.unit))
+                .interact()
+                .unwrap_or(false)
+    };
+    // a dry run reads the unit files like any other but writes nothing
+    let dry_run_files = DryRunFileSystem { inner: &OsFileSystem };
+    let files: &dyn FileSystem = if args.dry_run() { &dry_run_files } else { &OsFileSystem };
+    let syncer = Syncer {
+        config: &config,
+        preview: PreviewOptions {
+            render: args.source.render_options(&secrets, &templates),
+            unit_dir: &unit_dir,
+            files,
+            runner: &SystemRunner,
+            user: args.target.user,
+            hash_algo: args.hash_algo,
+        },
+        sync: SyncOptions {
+            unit_dir: &unit_dir,
+            backup_dir: &backup_dir,
+            files,
+            runner: &SystemRunner,
+            user: args.target.user,
+            store_content: args.store_content,
+            start_inactive: !args.no_start_inactive,
+            restart_timeout: args.restart_timeout.map(std::time::Duration::from_secs),
+            restart_delay: args.restart_delay.map(std::time::Duration::from_secs),
+            wait_active: args.wait_active,
+            keep_going: args.keep_going,
+            strict_hooks: args.strict_hooks,
+            create_dir: !args.no_create_dir,
+            audit_log: args.audit_log.as_deref(),
+        },
+        only: &args.only,
+        exclude: &args.exclude,
+        prune: !args.no_prune,
+        parallel: args.parallel,
+        transaction: args.transaction,
+        canary: args.canary.then_some(&confirm_canary as &dyn Fn(&ServiceChange) -> bool),
+        resume: journal.as_ref(),
+        force_all: args.force_all,
+    };
+    if args.source.strict_vars {
+        check_variables(&syncer.services(), &config.variables, &secrets, &args.source.set, &templates)?;
     }
     
-    println!("The following actions will be performed:");
-    for change in &changes {
-        if change.state_modified {
-            println!(" ! Override manual changes to: {}", change.unit);
+    if text && !args.quiet && !args.check {
+        println!("Analyzing changes...");
+    }
+    templates.warm(&syncer.services())?;
+    let mut plan = syncer.plan(&state)?;
+    for file in &plan.drift {
+        log::warn!("{} was modified outside of sdsync, though it matches the configuration", file);
+    }
+    
+    // a dry run only reports the drift through the diff warning
+    if let Some(change) = plan.drifted() {
+        if !args.force && !args.dry_run() {
+            return Err(ManagerError::StateOutOfSync(change.file.clone()));
         }
-        println!(" * Update service unit file: {}", change.unit);
-        println!(" * Reload systemd daemon");
-        println!(" * Restart service: {}", change.unit);
     }
     
-    if !Confirm::new()
-        .with_prompt("Do you want to apply these changes?")
-        .interact()? 
-    {
-        println!("Operation cancelled.");
-        return Ok(());
+    if args.verify {
+        for change in plan.changes.iter().filter(|change| change.content_changed()) {
+            // the copy checked goes to a temporary directory, so a dry run verifies too
+            verify_unit(change, &OsFileSystem, syncer.preview.runner, args.target.user)?;
+        }
     }
     
-    println!("Applying changes...");
-    for change in &changes {
-        println!("Updating service: {}", change.unit);
-        sync_service(change, &mut state)?;
+    if !text {
+        print_json_plan(&plan, &state)?;
     }
     
-    state.save(state_path)?;
+    if args.check {
+        if text && !args.quiet {
+            let files = plan.changes.iter().map(|change| &change.file).chain(&plan.removals).chain(&plan.masks).chain(&plan.unmasks);
+            for file in files {
+                println!(" ! {} would change", file);
+            }
+        }
+        return Ok(if plan.is_empty() { ExitCode::SUCCESS } else { ExitCode::from(EXIT_PENDING) });
+    }
     
-    println!("All changes applied successfully!");
+    if plan.is_empty() {
+        if !args.dry_run() {
+            Journal::remove(&journal_path, files)?;
+        }
+        if text {
+            println!("No changes needed for any services");
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
     
-    Ok(())
+    if text && !args.quiet {
+        println!("\nPlanned changes:");
+        // with --confirm-each every diff is shown with its own prompt instead
+        for change in plan.changes.iter().filter(|change| change.differs() && !args.confirm_each) {
+            print_diff(
+                change.old_content.as_deref(),
+                &change.new_content,
+                &change_title(change),
+                change.state_modified,
+                &diff_options,
+            );
+            if change.state_modified {
+                print_drift(change, &diff_options);
+            }
+        }
+        
+        println!("The following actions will be performed:");
+        for unit in &plan.unmasks {
+            println!(" * Unmask service: {}", unit);
+        }
+        // every unit file is written before the single daemon-reload, the restarts follow in order
+        for change in &plan.changes {
+            if change.state_modified {
+                println!(" ! Override manual changes to: {}", change.file);
+            }
+            if change.differs() {
+                println!(" * Update service unit file: {}", change.file);
+            } else if change.rewrite {
+                println!(" * Rewrite unchanged unit file: {}", change.file);
+            }
+        }
+        if plan.changes.iter().any(|change| change.content_changed()) {
+            println!(" * Reload systemd daemon");
+        }
+        // a unit is restarted once for all of its files
+        let mut restarted: Vec<&str> = Vec::new();
+        for change in &plan.changes {
+            if change.content_changed() && !restarted.contains(&change.unit.as_str()) {
+                restarted.push(&change.unit);
+                if let Some(description) = change.restart_mode.description() {
+                    let condition = if args.no_start_inactive { " if it is running" } else { "" };
+                    println!(" * {}{}: {}", description, condition, change.unit);
+                }
+            }
+            match change.enable {
+                Some(true) => println!(" * Enable service: {}", change.unit),
+                Some(false) => println!(" * Disable service: {}", change.unit),
+                None => {}
+            }
+        }
+        for unit in &plan.removals {
+            println!(" * Remove service: {}", unit);
+            println!(" * Reload systemd daemon");
+        }
+        for unit in &plan.masks {
+            println!(" * Mask service: {}", unit);
+        }
+    }
+    
+    if args.dry_run() {
+        if text {
+            println!("{}Dry run, no changes were applied.", if args.quiet { "" } else { "\n" });
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    
+    // keep stdout clean for the json plan, progress goes to stderr there
+    let status = |message: &str| if text { println!("{}", message) } else { eprintln!("{}", message) };
+    let progress = |message: &str| if !args.quiet { status(message) };
+    
+    if !args.yes {
+        if !io::stdin().is_terminal() {
+            return Err(ManagerError::NotInteractive);
+        }
+        
+        if args.confirm_each {
+            let Some(approved) = confirm_each(plan, &diff_options)? else {
+                status("Operation cancelled.");
+                return Ok(ExitCode::from(EXIT_DECLINED));
+            };
+            if approved.is_empty() {
+                status("No changes were approved.");
+                return Ok(ExitCode::from(EXIT_DECLINED));
+            }
+            plan = approved;
+        } else if !Confirm::new()
+            .with_prompt("Do you want to apply these changes?")
+            .interact()? 
+        {
+            status("Operation cancelled.");
+            return Ok(ExitCode::from(EXIT_DECLINED));
+        }
+    }
+    
+    progress("Applying changes...");
+    Journal::new(&plan).save(&journal_path, files)?;
+    let run = LastRun::new(&plan, &state, &unit_dir, files);
+    let result = syncer.apply(&plan, &mut state, &progress);
+    // keep whatever did succeed before reporting the failures
+    state.save(state_path, &OsFileSystem)?;
+    // a run that got nothing through leaves the previous one to undo
+    let run = run.finish(&state);
+    if !run.changes.is_empty() {
+        run.save(&LastRun::path(state_path), files)?;
+    }
+    // the run got to the end, failed or not, so there is nothing left to resume
+    Journal::remove(&journal_path, files)?;
+    
+    let summary = Summary::new(&syncer.services(), &plan, &state);
+    if !text {
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    } else if !args.quiet {
+        summary.print();
+    }
+    if let Some(url) = &args.notify_url {
+        match &result {
+            Ok(()) => notify(url, &summary, &plan, None),
+            Err(err) if args.notify_on_failure => notify(url, &summary, &plan, Some(err)),
+            Err(_) => {}
+        }
+    }
+    result?;
+    
+    status("All changes applied successfully!");
+    
+    Ok(ExitCode::SUCCESS)
 }
+
+// what apply exits with when the changes were shown but not confirmed
+const EXIT_DECLINED: u8 = 3;
+// what --check exits with when anything would change
+const EXIT_PENDING: u8 = 4;
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    
+    let level = match args.verbose {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        _ => log::LevelFilter::Debug,
+    };
+    env_logger::Builder::new().filter_level(level).init();
+    
+    // console already turns styling off for NO_COLOR and when the output isn't a terminal
+    if args.no_color {
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
+    }
+    if let Some(path) = &args.systemctl_path {
+        set_systemctl_path(path.clone());
+    }
+    
+    let result = match &args.command {
+        Some(Command::Apply(apply_args)) => apply(apply_args),
+        Some(Command::Diff(diff_args)) => diff(diff_args).map(|()| ExitCode::SUCCESS),
+        Some(Command::Status(status_args)) => status(status_args).map(|()| ExitCode::SUCCESS),
+        Some(Command::List(list_args)) => list(list_args).map(|()| ExitCode::SUCCESS),
+        Some(Command::Rollback(rollback_args)) => rollback(rollback_args).map(|()| ExitCode::SUCCESS),
+        Some(Command::Undo(undo_args)) => undo(undo_args),
+        Some(Command::Validate(validate_args)) => validate(validate_args).map(|()| ExitCode::SUCCESS),
+        Some(Command::Export(export_args)) => export(export_args).map(|()| ExitCode::SUCCESS),
+        Some(Command::Import(import_args)) => import(import_args).map(|()| ExitCode::SUCCESS),
+        Some(Command::Init(init_args)) => init(init_args).map(|()| ExitCode::SUCCESS),
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(*shell, &mut Args::command(), "sdsync", &mut io::stdout());
+            Ok(ExitCode::SUCCESS)
+        }
+        None => apply(&args.apply),
+    };
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
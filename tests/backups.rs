@@ -0,0 +1,51 @@
+mod common;
+
+use std::path::Path;
+
+use common::*;
+use sdsync::{backup_unit, find_backup, list_backups, ManagerError};
+
+#[test]
+fn backups_within_one_second_get_a_counter() {
+    let files = files();
+    files.insert(unit_path("web.service"), "[Service]\nExecStart=/usr/bin/web 1");
+    let first = backup_unit(&files, &unit_path("web.service"), "web.service", Path::new(BACKUP_DIR)).unwrap();
+    files.insert(unit_path("web.service"), "[Service]\nExecStart=/usr/bin/web 2");
+    let second = backup_unit(&files, &unit_path("web.service"), "web.service", Path::new(BACKUP_DIR)).unwrap();
+    
+    assert_ne!(first, second);
+    assert_eq!(files.get(&first).unwrap(), "[Service]\nExecStart=/usr/bin/web 1");
+    assert_eq!(files.get(&second).unwrap(), "[Service]\nExecStart=/usr/bin/web 2");
+    
+    let timestamps = list_backups(&files, "web.service", Path::new(BACKUP_DIR)).unwrap();
+    assert_eq!(timestamps.len(), 2);
+    assert_eq!(Path::new(BACKUP_DIR).join(format!("web.service.{}", timestamps[1])), second);
+}
+
+#[test]
+fn backups_are_listed_oldest_first() {
+    let files = files();
+    for suffix in ["100-1", "100", "99", "100-10", "100-2", "old", "100-"] {
+        files.insert(Path::new(BACKUP_DIR).join(format!("web.service.{}", suffix)), "");
+    }
+    files.insert(Path::new(BACKUP_DIR).join("db.service.101"), "");
+    
+    let timestamps = list_backups(&files, "web.service", Path::new(BACKUP_DIR)).unwrap();
+    assert_eq!(timestamps, ["99", "100", "100-1", "100-2", "100-10"]);
+}
+
+#[test]
+fn rollback_only_finds_listed_backups() {
+    let files = files();
+    files.insert(Path::new(BACKUP_DIR).join("web.service.100"), "[Service]\nExecStart=/usr/bin/web");
+    files.insert("/etc/shadow", "root:x");
+    
+    assert_eq!(
+        find_backup(&files, "web.service", Path::new(BACKUP_DIR), "100").unwrap(),
+        Path::new(BACKUP_DIR).join("web.service.100"),
+    );
+    for timestamp in ["101", "../../etc/shadow", "100/../100", ""] {
+        let result = find_backup(&files, "web.service", Path::new(BACKUP_DIR), timestamp);
+        assert!(matches!(result, Err(ManagerError::BackupNotFound(_))), "{:?} was found", timestamp);
+    }
+}
@@ -0,0 +1,126 @@
+// helpers shared by the integration tests, which run syncs against
+// MemoryFileSystem and RecordingRunner instead of the disk and systemctl
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use sdsync::{
+    load_config, preview_changes, Config, FileSystem, HashAlgo, ManagerError, MemoryFileSystem, PreviewOptions,
+    RecordingRunner, RenderOptions, ServiceChange, StateFile, SyncOptions, Syncer, Templates,
+};
+
+pub const UNIT_DIR: &str = "/units";
+pub const BACKUP_DIR: &str = "/backups";
+pub const TEMPLATE_DIR: &str = "/templates";
+
+// goes through load_config like a run does, so services get sorted by their dependencies
+pub fn config(yaml: &str) -> Config {
+    let files = MemoryFileSystem::new();
+    files.insert("/config.yml", yaml);
+    load_config(&["/config.yml".to_string()], &files).unwrap()
+}
+
+pub fn unit_path(file: &str) -> PathBuf {
+    Path::new(UNIT_DIR).join(file)
+}
+
+// a file system with an empty unit directory
+pub fn files() -> MemoryFileSystem {
+    let files = MemoryFileSystem::new();
+    files.create_dir_all(Path::new(UNIT_DIR)).unwrap();
+    files
+}
+
+pub fn changes(config: &Config, files: &dyn FileSystem, runner: &RecordingRunner, state: &StateFile) -> Vec<ServiceChange> {
+    let templates = Templates::new(Path::new("templates"), false);
+    let secrets = BTreeMap::new();
+    let options = preview_options(&templates, &secrets, files, runner);
+    config.services
+        .iter()
+        .map(|service| preview_changes(service, &config.variables, &options, state).unwrap())
+        .collect()
+}
+
+// plans and applies the whole configuration through a Syncer like a run does, with the
+// templates read from TEMPLATE_DIR in `files`
+pub fn apply(
+    config: &Config,
+    files: &Arc<MemoryFileSystem>,
+    runner: &RecordingRunner,
+    state: &mut StateFile,
+    parallel: Option<usize>,
+) -> Result<(), ManagerError> {
+    let templates = Templates::new(Path::new(TEMPLATE_DIR), false).with_file_system(files.clone());
+    let secrets = BTreeMap::new();
+    let syncer = Syncer {
+        config,
+        preview: preview_options(&templates, &secrets, &**files, runner),
+        sync: sync_options(&**files, runner),
+        only: &[],
+        exclude: &[],
+        prune: true,
+        parallel,
+        transaction: false,
+        canary: None,
+        resume: None,
+        force_all: false,
+    };
+    let plan = syncer.plan(state)?;
+    syncer.apply(&plan, state, &|_| {})
+}
+
+fn preview_options<'a>(
+    templates: &'a Templates,
+    secrets: &'a BTreeMap<String, serde_yaml::Value>,
+    files: &'a dyn FileSystem,
+    runner: &'a RecordingRunner,
+) -> PreviewOptions<'a> {
+    PreviewOptions {
+        render: RenderOptions {
+            templates,
+            expand_env: false,
+            secrets,
+            overrides: &[],
+            strict_syntax: false,
+            strict_sections: false,
+        },
+        unit_dir: Path::new(UNIT_DIR),
+        files,
+        runner,
+        user: true,
+        hash_algo: HashAlgo::default(),
+    }
+}
+
+pub fn sync_options<'a>(files: &'a dyn FileSystem, runner: &'a RecordingRunner) -> SyncOptions<'a> {
+    SyncOptions {
+        unit_dir: Path::new(UNIT_DIR),
+        backup_dir: Path::new(BACKUP_DIR),
+        files,
+        runner,
+        user: true,
+        store_content: false,
+        start_inactive: true,
+        restart_timeout: None,
+        restart_delay: None,
+        wait_active: false,
+        keep_going: false,
+        strict_hooks: false,
+        create_dir: false,
+        audit_log: None,
+    }
+}
+
+// the systemctl calls, without the `systemctl --user` every one of them starts with
+pub fn systemctl_calls(runner: &RecordingRunner) -> Vec<String> {
+    runner.calls()
+        .into_iter()
+        .filter_map(|call| call.strip_prefix("systemctl --user ").map(str::to_string))
+        .collect()
+}
+
+pub fn position(calls: &[String], call: &str) -> usize {
+    calls.iter().position(|candidate| candidate == call).unwrap_or_else(|| panic!("{} wasn't run: {:?}", call, calls))
+}
@@ -0,0 +1,102 @@
+mod common;
+
+use common::*;
+use sdsync::{calculate_hash, DryRunFileSystem, FileSystem, HashAlgo, RecordingRunner, ServiceState, StateFile};
+
+const SERVICE: &str = r#"
+services:
+  - unit: web.service
+    template_inline: "[Service]\nExecStart=/usr/bin/web {{ port }}\n"
+    variables: {port: 8080}
+"#;
+
+const RENDERED: &str = "[Service]\nExecStart=/usr/bin/web 8080";
+
+// a state that says `content` is what the last apply wrote
+fn applied(content: &str) -> StateFile {
+    let mut state = StateFile::default();
+    state.services.insert(
+        "web.service".to_string(),
+        ServiceState::new(calculate_hash(content, HashAlgo::default()), HashAlgo::default()),
+    );
+    state
+}
+
+#[test]
+fn new_unit_has_no_old_content() {
+    let config = config(SERVICE);
+    let files = files();
+    let runner = RecordingRunner::new();
+    let changes = changes(&config, &files, &runner, &StateFile::default());
+    
+    assert_eq!(changes[0].old_content, None);
+    assert_eq!(changes[0].new_content, RENDERED);
+    assert!(changes[0].content_changed());
+    assert!(!changes[0].state_modified);
+}
+
+#[test]
+fn unit_matching_the_state_is_unchanged() {
+    let config = config(SERVICE);
+    let files = files();
+    files.insert(unit_path("web.service"), RENDERED);
+    let runner = RecordingRunner::new();
+    let changes = changes(&config, &files, &runner, &applied(RENDERED));
+    
+    assert_eq!(changes[0].old_content.as_deref(), Some(RENDERED));
+    assert!(!changes[0].content_changed());
+    assert!(!changes[0].state_modified);
+}
+
+#[test]
+fn edit_on_disk_is_reported_as_drift() {
+    let config = config(SERVICE);
+    let files = files();
+    let edited = "[Service]\nExecStart=/usr/bin/web 9090";
+    files.insert(unit_path("web.service"), edited);
+    let runner = RecordingRunner::new();
+    let changes = changes(&config, &files, &runner, &applied(RENDERED));
+    
+    assert_eq!(changes[0].old_content.as_deref(), Some(edited));
+    assert!(changes[0].content_changed());
+    assert!(changes[0].state_modified);
+}
+
+#[test]
+fn dry_run_file_system_leaves_the_unit_dir_alone() {
+    let config = config(SERVICE);
+    let files = files();
+    let edited = "[Service]\nExecStart=/usr/bin/web 9090";
+    files.insert(unit_path("web.service"), edited);
+    let dry_run = DryRunFileSystem { inner: &files };
+    let runner = RecordingRunner::new();
+    let changes = changes(&config, &dry_run, &runner, &applied(RENDERED));
+    
+    // reads still go through to the real files
+    assert_eq!(changes[0].old_content.as_deref(), Some(edited));
+    assert!(changes[0].state_modified);
+    
+    dry_run.write(&unit_path("web.service"), &changes[0].new_content, None).unwrap();
+    dry_run.copy(&unit_path("web.service"), &unit_path("web.service.bak")).unwrap();
+    dry_run.remove_file(&unit_path("web.service")).unwrap();
+    assert_eq!(files.get(&unit_path("web.service")).unwrap(), edited);
+    assert!(!files.exists(&unit_path("web.service.bak")));
+}
+
+#[test]
+fn existing_entry_keeps_its_hash_algo() {
+    let config = config(SERVICE);
+    let files = files();
+    files.insert(unit_path("web.service"), RENDERED);
+    let mut state = StateFile::default();
+    state.services.insert(
+        "web.service".to_string(),
+        ServiceState::new(calculate_hash(RENDERED, HashAlgo::Blake3), HashAlgo::Blake3),
+    );
+    let runner = RecordingRunner::new();
+    let changes = changes(&config, &files, &runner, &state);
+    
+    assert_eq!(changes[0].hash_algo, HashAlgo::Blake3);
+    assert!(!changes[0].content_changed());
+    assert!(!changes[0].state_modified);
+}
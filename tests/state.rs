@@ -0,0 +1,52 @@
+mod common;
+
+use std::path::Path;
+use std::time::Duration;
+
+use common::*;
+use sdsync::{FileSystem, Journal, ManagerError, MemoryFileSystem, Plan, RecordingRunner, StateFile, StateLock};
+
+const STATE: &str = "/state/state.yml";
+
+#[test]
+fn state_lock_is_held_until_dropped() {
+    let files = files();
+    files.create_dir_all(Path::new("/state")).unwrap();
+    
+    let lock = StateLock::acquire(Path::new(STATE), Duration::ZERO, &files).unwrap();
+    let second = StateLock::acquire(Path::new(STATE), Duration::ZERO, &files);
+    assert!(matches!(second, Err(ManagerError::StateLocked(_))));
+    
+    drop(lock);
+    assert!(StateLock::acquire(Path::new(STATE), Duration::ZERO, &files).is_ok());
+}
+
+#[test]
+fn journal_and_state_round_trip_through_the_file_system() {
+    let config = config(r#"
+services:
+  - unit: web.service
+    template_inline: "[Service]\nExecStart=/usr/bin/web\n"
+"#);
+    let files = MemoryFileSystem::new();
+    files.create_dir_all(Path::new("/state")).unwrap();
+    let runner = RecordingRunner::new();
+    let state = StateFile::default();
+    let plan = Plan {
+        changes: changes(&config, &files, &runner, &state),
+        ..Plan::default()
+    };
+    let journal_path = Journal::path(Path::new(STATE));
+    
+    Journal::new(&plan).save(&journal_path, &files).unwrap();
+    let journal = Journal::load(&journal_path, &files).unwrap().unwrap();
+    assert_eq!(journal.changes.len(), 1);
+    assert_eq!(journal.changes[0].file, "web.service");
+    
+    Journal::remove(&journal_path, &files).unwrap();
+    assert!(Journal::load(&journal_path, &files).unwrap().is_none());
+    
+    state.save(Path::new(STATE), &files).unwrap();
+    assert!(files.get(Path::new(STATE)).is_some());
+    assert!(StateFile::load_or_create(Path::new(STATE), &files).unwrap().services.is_empty());
+}
@@ -0,0 +1,226 @@
+mod common;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use common::*;
+use sdsync::{
+    sync_parallel, sync_sequential, sync_transaction, CommandOutput, ManagerError, MemoryFileSystem, RecordingRunner,
+    StateFile,
+};
+
+const SERVICES: &str = r#"
+services:
+  - unit: web.service
+    depends_on: [db.service]
+    template_inline: "[Service]\nExecStart=/usr/bin/web {{ port }}\n"
+    variables: {port: 8080}
+  - unit: db.service
+    template_inline: "[Service]\nExecStart=/usr/bin/db\n"
+"#;
+
+// app.service with an environment file next to it, rendered from TEMPLATE_DIR
+const WITH_ENVIRONMENT_FILE: &str = r#"
+services:
+  - unit: app.service
+    template_inline: "[Service]\nEnvironmentFile=/units/app.env\nExecStart=/usr/bin/app\n"
+    environment_file: {template: app.env, path: app.env}
+    variables: {port: 8080}
+"#;
+
+fn with_templates() -> Arc<MemoryFileSystem> {
+    let files = files();
+    files.insert(Path::new(TEMPLATE_DIR).join("app.env"), "PORT={{ port }}\n");
+    Arc::new(files)
+}
+
+fn failing() -> CommandOutput {
+    CommandOutput {
+        code: Some(1),
+        stdout: String::new(),
+        stderr: "failed".to_string(),
+    }
+}
+
+#[test]
+fn sequential_reloads_once_and_restarts_in_dependency_order() {
+    let config = config(SERVICES);
+    let files = files();
+    let runner = RecordingRunner::new();
+    let mut state = StateFile::default();
+    let changes = changes(&config, &files, &runner, &state);
+    
+    sync_sequential(&changes, &sync_options(&files, &runner), &mut state, &|_| {}).unwrap();
+    
+    assert_eq!(systemctl_calls(&runner), [
+        "daemon-reload",
+        "restart db.service",
+        "restart web.service",
+    ]);
+    assert_eq!(files.get(&unit_path("web.service")).unwrap(), "[Service]\nExecStart=/usr/bin/web 8080");
+    assert!(state.services.contains_key("db.service"));
+    assert!(state.services.contains_key("web.service"));
+}
+
+#[test]
+fn parallel_restarts_dependents_after_their_dependencies() {
+    let config = config(&format!("{}{}", SERVICES, r#"
+  - unit: cache.service
+    template_inline: "[Service]\nExecStart=/usr/bin/cache\n"
+"#));
+    let files = files();
+    let runner = RecordingRunner::new();
+    let mut state = StateFile::default();
+    let changes = changes(&config, &files, &runner, &state);
+    
+    sync_parallel(&changes, &sync_options(&files, &runner), 4, &mut state, &|_| {}).unwrap();
+    
+    let calls = systemctl_calls(&runner);
+    assert_eq!(calls.iter().filter(|call| *call == "daemon-reload").count(), 1);
+    assert_eq!(calls[0], "daemon-reload");
+    assert!(position(&calls, "restart db.service") < position(&calls, "restart web.service"));
+    assert!(calls.contains(&"restart cache.service".to_string()));
+    assert_eq!(state.services.len(), 3);
+}
+
+#[test]
+fn sequential_restores_the_unit_file_when_the_restart_fails() {
+    let config = config(SERVICES);
+    let files = files();
+    files.insert(unit_path("web.service"), "[Service]\nExecStart=/usr/bin/web 80\n");
+    let runner = RecordingRunner::new();
+    runner.respond("systemctl --user restart web.service", failing());
+    let mut state = StateFile::default();
+    let changes = changes(&config, &files, &runner, &state);
+    
+    let result = sync_sequential(&changes, &sync_options(&files, &runner), &mut state, &|_| {});
+    
+    assert!(matches!(result, Err(ManagerError::RestartFailed { ref unit, .. }) if unit == "web.service"));
+    assert_eq!(files.get(&unit_path("web.service")).unwrap(), "[Service]\nExecStart=/usr/bin/web 80\n");
+    assert_eq!(systemctl_calls(&runner), [
+        "daemon-reload",
+        "restart db.service",
+        "restart web.service",
+        "daemon-reload",
+    ]);
+    // db came up before web failed, so it stays applied
+    assert!(state.services.contains_key("db.service"));
+    assert!(!state.services.contains_key("web.service"));
+}
+
+#[test]
+fn parallel_skips_only_the_service_whose_pre_hook_fails() {
+    let config = config(r#"
+services:
+  - unit: db.service
+    template_inline: "[Service]\nExecStart=/usr/bin/db\n"
+  - unit: web.service
+    pre_hook: "exit 1"
+    template_inline: "[Service]\nExecStart=/usr/bin/web\n"
+"#);
+    let files = files();
+    let runner = RecordingRunner::new();
+    runner.respond("sh -c exit 1", failing());
+    let mut state = StateFile::default();
+    let changes = changes(&config, &files, &runner, &state);
+    
+    let result = sync_parallel(&changes, &sync_options(&files, &runner), 2, &mut state, &|_| {});
+    
+    assert!(matches!(result, Err(ManagerError::ServicesFailed(ref errors)) if errors.len() == 1));
+    assert_eq!(files.get(&unit_path("db.service")).unwrap(), "[Service]\nExecStart=/usr/bin/db");
+    assert_eq!(files.get(&unit_path("web.service")), None);
+    assert_eq!(systemctl_calls(&runner), ["daemon-reload", "restart db.service"]);
+    assert!(state.services.contains_key("db.service"));
+    assert!(!state.services.contains_key("web.service"));
+}
+
+#[test]
+fn sequential_skips_only_the_service_whose_pre_hook_fails() {
+    let config = config(r#"
+services:
+  - unit: web.service
+    pre_hook: "exit 1"
+    template_inline: "[Service]\nExecStart=/usr/bin/web\n"
+  - unit: db.service
+    template_inline: "[Service]\nExecStart=/usr/bin/db\n"
+"#);
+    let files = files();
+    let runner = RecordingRunner::new();
+    runner.respond("sh -c exit 1", failing());
+    let mut state = StateFile::default();
+    let changes = changes(&config, &files, &runner, &state);
+    
+    let result = sync_sequential(&changes, &sync_options(&files, &runner), &mut state, &|_| {});
+    
+    assert!(matches!(result, Err(ManagerError::ServicesFailed(ref errors)) if errors.len() == 1));
+    assert_eq!(files.get(&unit_path("web.service")), None);
+    assert_eq!(files.get(&unit_path("db.service")).unwrap(), "[Service]\nExecStart=/usr/bin/db");
+    assert_eq!(systemctl_calls(&runner), ["daemon-reload", "restart db.service"]);
+    assert!(state.services.contains_key("db.service"));
+    assert!(!state.services.contains_key("web.service"));
+}
+
+#[test]
+fn transaction_rolls_back_every_service_when_one_fails() {
+    let config = config(SERVICES);
+    let files = files();
+    files.insert(unit_path("db.service"), "[Service]\nExecStart=/usr/bin/db --old\n");
+    let runner = RecordingRunner::new();
+    runner.respond("systemctl --user restart web.service", failing());
+    let mut state = StateFile::default();
+    let changes = changes(&config, &files, &runner, &state);
+    
+    let result = sync_transaction(&changes, &sync_options(&files, &runner), &mut state, &|_| {});
+    
+    assert!(result.is_err());
+    assert_eq!(files.get(&unit_path("db.service")).unwrap(), "[Service]\nExecStart=/usr/bin/db --old\n");
+    assert_eq!(files.get(&unit_path("web.service")), None);
+    // web's own restore is reloaded right away, db was already restarted on its
+    // new unit file, so it is put back, reloaded and restarted again on the old one
+    assert_eq!(systemctl_calls(&runner), [
+        "daemon-reload",
+        "restart db.service",
+        "restart web.service",
+        "daemon-reload",
+        "daemon-reload",
+        "restart db.service",
+    ]);
+    assert!(state.services.is_empty());
+}
+
+#[test]
+fn unit_and_its_environment_file_restart_the_unit_once() {
+    for parallel in [None, Some(4)] {
+        let config = config(WITH_ENVIRONMENT_FILE);
+        let files = with_templates();
+        let runner = RecordingRunner::new();
+        let mut state = StateFile::default();
+        
+        apply(&config, &files, &runner, &mut state, parallel).unwrap();
+        
+        assert_eq!(files.get(&unit_path("app.env")).unwrap(), "PORT=8080");
+        assert_eq!(systemctl_calls(&runner), ["daemon-reload", "restart app.service"], "parallel: {:?}", parallel);
+        assert!(state.services.contains_key("app.service"));
+        assert!(state.services.contains_key("app.env"));
+    }
+}
+
+#[test]
+fn global_hooks_run_once_per_unit() {
+    for parallel in [None, Some(4)] {
+        let config = config(&format!("pre_hook: echo pre\npost_hook: echo post\n{}{}", WITH_ENVIRONMENT_FILE, r#"
+  - unit: db.service
+    template_inline: "[Service]\nExecStart=/usr/bin/db\n"
+"#));
+        let files = with_templates();
+        let runner = RecordingRunner::new();
+        let mut state = StateFile::default();
+        
+        apply(&config, &files, &runner, &mut state, parallel).unwrap();
+        
+        let calls = runner.calls();
+        assert_eq!(calls.iter().filter(|call| *call == "sh -c echo pre").count(), 2, "parallel: {:?}", parallel);
+        assert_eq!(calls.iter().filter(|call| *call == "sh -c echo post").count(), 2, "parallel: {:?}", parallel);
+        assert_eq!(systemctl_calls(&runner).iter().filter(|call| call.starts_with("restart ")).count(), 2);
+    }
+}
@@ -0,0 +1,30 @@
+mod common;
+
+use std::path::Path;
+
+use common::*;
+use sdsync::{unused_templates, MemoryFileSystem};
+
+#[test]
+fn only_includes_and_file_calls_in_template_code_are_used() {
+    let config = config(r#"
+services:
+  - unit: web.service
+    template: web.service
+"#);
+    let files = MemoryFileSystem::new();
+    files.insert("/templates/web.service", r#"[Service]
+{% include "inc.conf" %}
+{# {% include "commented.conf" %} #}
+Environment=TOKEN={{ file("token") | trim }}
+Environment=PROFILE={{ profile("profile.txt") }}
+Environment=TEXT={{ "file('quoted.txt')" }}
+ExecStart=/usr/bin/web file("plain.txt")
+"#);
+    for name in ["inc.conf", "commented.conf", "token", "profile.txt", "quoted.txt", "plain.txt"] {
+        files.insert(Path::new("/templates").join(name), "");
+    }
+    
+    let unused = unused_templates(&config, Path::new("/templates"), &files).unwrap();
+    assert_eq!(unused, ["commented.conf", "plain.txt", "profile.txt", "quoted.txt"]);
+}